@@ -89,13 +89,16 @@ def main():
     """Main function"""
 
     parser = holly.HollyParser()
+    last_seq = None
+    epoch = None
 
     while True:
         try:
-            client = holly.HollyClient()
+            client = holly.HollyClient(resume_from=last_seq, epoch=epoch)
             print('Connected to Holly')
             while True:
                 raw_msg = client.recv()
+                last_seq, epoch = client.last_seq, client.epoch
                 print(raw_msg)
                 ret = process_message(raw_msg.parse(parser))
                 if ret:
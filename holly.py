@@ -4,6 +4,7 @@ Authored by Jackson Coxson
 """
 
 import json
+import uuid
 import socket
 import re
 from typing import Union
@@ -11,19 +12,6 @@ import itertools
 
 DEFAULT_JUNK = ["a", "an", "are", "as", "is", "the"]
 
-def jsonsplit(s):
-    d = 0
-    t = ""
-    for i in s:
-        t += i
-        if i == "{":d+=1
-        if i == "}":
-            d -= 1
-            if d < 0: raise Exception("Invalid JSON")
-            if d == 0 and t != "":
-                yield json.loads(t)
-                t = ""
-
 class HollyError(Exception):
     """Exception raised for errors in the Holly module.
 
@@ -199,6 +187,15 @@ class HollyMessage:
         content: The content of the message.
         chat_id: Identifier of the chat the message belongs to.
         sender: Sender of the message.
+        message_id: Messenger's ID for the message, if Holly found one.
+        reply_to: Dict with the "sender", "content" and maybe "message_id" of
+        the message this one replied to, or None if it isn't a reply.
+        attachments: List of dicts describing photos, videos, voice clips and
+        files sent with the message, each with a "kind" and "url".
+        reactions: List of emoji people have reacted to the message with.
+        from_self: Whether Holly sent the message herself.
+        sent_at: RFC 3339 timestamp of when the message was sent, if known.
+        detected_at: RFC 3339 timestamp of when Holly noticed the message.
     """
 
     def __init__(
@@ -212,10 +209,24 @@ class HollyMessage:
             self.content = json_data["content"]
             self.chat_id = json_data["chat_id"]
             self.sender = json_data["sender"]
+            self.message_id = json_data.get("message_id")
+            self.reply_to = json_data.get("reply_to")
+            self.attachments = json_data.get("attachments", [])
+            self.reactions = json_data.get("reactions", [])
+            self.from_self = json_data.get("from_self", False)
+            self.sent_at = json_data.get("sent_at")
+            self.detected_at = json_data.get("detected_at")
         else:
             self.content = content
             self.chat_id = chat_id
             self.sender = sender
+            self.message_id = None
+            self.reply_to = None
+            self.attachments = []
+            self.reactions = []
+            self.from_self = False
+            self.sent_at = None
+            self.detected_at = None
 
     def __str__(self):
         return str(self.to_dict())
@@ -233,15 +244,47 @@ class HollyMessage:
             "content": self.content,
             "chat_id": self.chat_id,
             "sender": self.sender,
+            "message_id": self.message_id,
+            "reply_to": self.reply_to,
+            "attachments": self.attachments,
+            "reactions": self.reactions,
+            "from_self": self.from_self,
+            "sent_at": self.sent_at,
+            "detected_at": self.detected_at,
         }
 
-    def serialize(self):
-        """Serializes the message to JSON format.
+    def to_request(self):
+        """Converts the message to a send_message request for Holly.
+
+        Returns:
+            dict: A dictionary representation of the request.
+        """
+        return {
+            "type": "send_message",
+            "chat_id": self.chat_id,
+            "content": self.content,
+        }
+
+    def to_target(self):
+        """Picks out this message in its chat, for replying or reacting to it.
+
+        Returns:
+            dict: Its message_id if Holly found one, otherwise its sender and content.
+        """
+        if self.message_id is not None:
+            return {"message_id": self.message_id}
+        return {"sender": self.sender, "content": self.content}
+
+    def to_own_target(self):
+        """Picks out this message among Holly's own, for editing or unsending it.
+        Only her own messages are matched, so the sender isn't needed.
 
         Returns:
-            bytes: The serialized message in JSON format encoded in UTF-8.
+            dict: Its message_id if Holly found one, otherwise its content.
         """
-        return json.dumps(self.to_dict()).encode("utf-8")
+        if self.message_id is not None:
+            return {"message_id": self.message_id}
+        return {"content": self.content}
 
     def parse(self, parser: HollyParser) -> ParsedHollyMessage:
         """Parses the message with the given HollyParser
@@ -261,28 +304,86 @@ class HollyClient:
         socket: The socket object for communication.
     """
 
-    def __init__(self, host="localhost", port=8011):
+    def __init__(
+        self,
+        host="localhost",
+        port=8011,
+        token=None,
+        unix_path=None,
+        resume_from=None,
+        epoch=None,
+    ):
         """
         Initializes the HollyClient instance and connects to the server.
 
         Args:
             host (str): The host address of the server. Default is 'localhost'.
             port (int): The port number of the server. Default is 8011.
+            token (str): Token from Holly's config to authenticate with, if any.
+            unix_path (str): Path of Holly's Unix domain socket. If given,
+            this is connected to instead of the host and port.
+            resume_from (int): Sequence number of the last message seen by a
+            previous connection. Holly replays everything sent after it.
+            epoch (int): The epoch resume_from was counted in, from the
+            previous connection's epoch attribute. If Holly has restarted
+            since, she replays everything she has.
 
         Raises:
             HollyError: If connection to the server fails.
         """
         self.host = host
         self.port = port
-        self.cache: list[HollyMessage] = []
+        self.buffer = b""
+        # Events read while waiting for an answer, handed out by recv_event later
+        self.pending = []
+        self.last_seq = resume_from
+        self.epoch = epoch
+        try:
+            if unix_path is not None:
+                self.socket = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+                self.socket.connect(unix_path)
+            else:
+                self.socket = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+                self.socket.connect((host, port))
+        except (ConnectionRefusedError, FileNotFoundError) as e:
+            where = unix_path if unix_path is not None else f"{host}:{port}"
+            raise HollyError(f"Connection to server at {where} refused.") from e
+        if token is not None:
+            self.request({"type": "auth", "token": token})
+        if resume_from is not None:
+            req = {"type": "resume", "resume_from": resume_from}
+            if epoch is not None:
+                req["epoch"] = epoch
+            self.request(req)
+
+    def recv_event(self) -> dict:
+        """Receives the next event of any type from the server.
+
+        Returns:
+            dict: The received event, with its kind in the "type" key.
+
+        Raises:
+            HollyError: If there's an issue receiving the event.
+        """
+        if self.pending:
+            return self.pending.pop(0)
         try:
-            self.socket = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
-            self.socket.connect((host, port))
-        except ConnectionRefusedError as e:
-            raise HollyError(f"Connection to server at {host}:{port} refused.") from e
+            while b"\n" not in self.buffer:
+                data = self.socket.recv(4096)
+                if not data:
+                    raise HollyError("Connection closed by Holly.")
+                self.buffer += data
+            line, self.buffer = self.buffer.split(b"\n", 1)
+            return json.loads(line.decode("utf-8"))
+        except HollyError:
+            raise
+        except json.JSONDecodeError as e:
+            raise HollyError("Failed to decode received message.") from e
+        except Exception as e:
+            raise HollyError(f"Failed to receive message: {e}") from e
 
     def recv(self) -> HollyMessage:
-        """Receives a message from the server.
+        """Receives a message from the server, skipping any other events.
 
         Returns:
             HollyMessage: The received message.
@@ -290,57 +391,269 @@ class HollyClient:
         Raises:
             HollyError: If there's an issue receiving the message.
         """
-        if len(self.cache) > 0:
-            msg = self.cache.pop(0)
-            return HollyMessage(json_data=msg)
+        while True:
+            event = self.recv_event()
+            if event.get("type", "message") == "message":
+                if "seq" in event:
+                    if event.get("epoch") != self.epoch:
+                        # Holly restarted and started counting over, so nothing is a repeat
+                        self.epoch = event.get("epoch")
+                        self.last_seq = None
+                    if self.last_seq is not None and event["seq"] <= self.last_seq:
+                        # Already seen before reconnecting
+                        continue
+                    self.last_seq = event["seq"]
+                return HollyMessage(json_data=event)
+
+    def wait_for(self, request_id: str):
+        """Waits for the "ack" or "error" event answering a request,
+        keeping any other events for recv.
+
+        Returns:
+            The result of the ack, if any.
+
+        Raises:
+            HollyError: If Holly answers with an error.
+        """
+        skipped = []
         try:
-            data = self.socket.recv(2048)
-            it = jsonsplit(data.decode("utf-8"))
-            msg = next(it)
-            if msg:
-                self.cache.extend(list(it))
-                return HollyMessage(json_data=msg)
-            else:
-                raise HollyError("No valid JSON recieved from network.")
-        except json.JSONDecodeError as e:
-            raise HollyError("Failed to decode received message.") from e
+            while True:
+                event = self.recv_event()
+                if event.get("request_id") != request_id:
+                    skipped.append(event)
+                elif event.get("type") == "error":
+                    raise HollyError(event.get("message", "Request failed"))
+                else:
+                    return event.get("result")
+        finally:
+            self.pending.extend(skipped)
+
+    def history(
+        self, chat_id=None, text=None, sender=None, since=None, until=None, limit=None
+    ) -> list[dict]:
+        """Searches the archive of every message Holly has read or sent.
+        Every argument that's given must match.
+
+        Args:
+            chat_id (str): Only search this chat.
+            text (str): Text the content contains, ignoring case.
+            sender (str): Exact sender name.
+            since (str): RFC 3339 timestamp to search from.
+            until (str): RFC 3339 timestamp to search up to.
+            limit (int): How many of the most recent matches to return. Default is 100.
+
+        Returns:
+            list[dict]: The matching messages, oldest first. Each has an "at"
+            timestamp and a "sent" flag alongside the usual message fields.
+        """
+        request_id = str(uuid.uuid4())
+        query = {
+            "chat_id": chat_id,
+            "text": text,
+            "sender": sender,
+            "since": since,
+            "until": until,
+            "limit": limit,
+        }
+        req = {k: v for k, v in query.items() if v is not None}
+        req["type"] = "history"
+        req["request_id"] = request_id
+        self.request(req)
+        return self.wait_for(request_id)["messages"]
+
+    def backfill(self, chat_id: str, limit: int = None, since: str = None):
+        """Scrolls up a chat to read its older messages.
+        Messages are yielded a page at a time, newest page first,
+        with each page oldest first.
+
+        Args:
+            chat_id (str): The chat to read.
+            limit (int): Most messages to read. Default is 500.
+            since (str): RFC 3339 timestamp to stop scrolling back at.
+
+        Yields:
+            HollyMessage: The older messages.
+
+        Raises:
+            HollyError: If Holly is unable to backfill the chat.
+        """
+        request_id = str(uuid.uuid4())
+        req = {"type": "backfill", "request_id": request_id, "chat_id": chat_id}
+        if limit is not None:
+            req["limit"] = limit
+        if since is not None:
+            req["since"] = since
+        self.request(req)
+
+        skipped = []
+        try:
+            while True:
+                event = self.recv_event()
+                if event.get("request_id") != request_id:
+                    skipped.append(event)
+                elif event.get("type") == "backfill":
+                    for message in event["messages"]:
+                        yield HollyMessage(json_data=message)
+                elif event.get("type") == "error":
+                    raise HollyError(event.get("message", "Backfill failed"))
+                else:
+                    return
+        finally:
+            self.pending.extend(skipped)
+
+    def download_attachment(self, url: str) -> str:
+        """Has Holly download an attachment with her cookies.
+
+        Args:
+            url (str): The "url" of one of a message's attachments.
+
+        Returns:
+            str: Where Holly saved the file.
+
+        Raises:
+            HollyError: If Holly is unable to download it.
+        """
+        request_id = str(uuid.uuid4())
+        self.request(
+            {"type": "download_attachment", "request_id": request_id, "url": url}
+        )
+        return self.wait_for(request_id)["path"]
+
+    def request(self, req: dict):
+        """Sends a raw request to the server.
+
+        Args:
+            req (dict): The request, with its kind in the "type" key.
+
+        Raises:
+            HollyError: If there's an issue sending the request.
+        """
+        try:
+            self.socket.sendall(json.dumps(req).encode("utf-8") + b"\n")
         except Exception as e:
-            raise HollyError(f"Failed to receive message: {e}") from e
+            raise HollyError(f"Failed to send message: {e}") from e
 
-    def send(self, msg: HollyMessage):
+    def send(self, msg: HollyMessage, request_id: str = None, reply_to: HollyMessage = None):
         """Sends a message to the server.
 
         Args:
             msg (HollyMessage): The message to be sent.
+            request_id (str): If given, Holly answers with an "ack" or
+            "error" event carrying this id once the message is sent.
+            reply_to (HollyMessage): A message received from Holly to reply to.
 
         Raises:
             HollyError: If there's an issue sending the message.
         """
-        try:
-            self.socket.send(msg.serialize())
-        except Exception as e:
-            raise HollyError(f"Failed to send message: {e}") from e
+        req = msg.to_request()
+        if request_id is not None:
+            req["request_id"] = request_id
+        if reply_to is not None:
+            req["reply_to"] = reply_to.to_target()
+        self.request(req)
+
+    def react(self, msg: HollyMessage, emoji: str, request_id: str = None):
+        """Reacts to a message with an emoji from Messenger's reaction bar.
+
+        Args:
+            msg (HollyMessage): A message received from Holly to react to.
+            emoji (str): The emoji to react with, like "👍".
+            request_id (str): If given, Holly answers with an "ack" or
+            "error" event carrying this id once the reaction is made.
+        """
+        req = {
+            "type": "react",
+            "chat_id": msg.chat_id,
+            "target": msg.to_target(),
+            "emoji": emoji,
+        }
+        if request_id is not None:
+            req["request_id"] = request_id
+        self.request(req)
+
+    def edit_message(self, msg: HollyMessage, content: str, request_id: str = None):
+        """Replaces the text of one of Holly's own messages.
+
+        Args:
+            msg (HollyMessage): The message Holly sent, such as the one passed to send.
+            content (str): The new text.
+            request_id (str): If given, Holly answers with an "ack" or
+            "error" event carrying this id once the message is edited.
+        """
+        req = {
+            "type": "edit_message",
+            "chat_id": msg.chat_id,
+            "target": msg.to_own_target(),
+            "content": content,
+        }
+        if request_id is not None:
+            req["request_id"] = request_id
+        self.request(req)
+
+    def unsend_message(self, msg: HollyMessage, request_id: str = None):
+        """Removes one of Holly's own messages for everyone.
+
+        Args:
+            msg (HollyMessage): The message Holly sent, such as the one passed to send.
+            request_id (str): If given, Holly answers with an "ack" or
+            "error" event carrying this id once the message is gone.
+        """
+        req = {
+            "type": "unsend_message",
+            "chat_id": msg.chat_id,
+            "target": msg.to_own_target(),
+        }
+        if request_id is not None:
+            req["request_id"] = request_id
+        self.request(req)
 
     def close(self):
         """Closes the connection to the server."""
         self.socket.close()
 
+    def subscribe(self, chat_ids=None, senders=None, patterns=None, reactions=False):
+        """Only receive messages matching the given filters.
+        Every list that isn't empty needs a match, and calling this
+        with no arguments receives everything again.
+
+        Args:
+            chat_ids (list[str]): Chats to listen to.
+            senders (list[str]): Exact sender names to listen to.
+            patterns (list[str]): Regular expressions matched against the content.
+            reactions (bool): Also receive "reactions" events from recv_event
+            when the reactions on a matching message change.
+        """
+        self.request({
+            "type": "subscribe",
+            "chat_ids": chat_ids or [],
+            "senders": senders or [],
+            "patterns": patterns or [],
+            "reactions": reactions,
+        })
+
+    def command(self, command: str):
+        """Sends a control command to Holly core"""
+        self.request({"type": "command", "command": command})
+
     def screenshot(self):
         """Command Holly core to take a screenshot"""
-        self.send(HollyMessage("", "", "<screenshot>"))
+        self.command("screenshot")
 
     def html(self):
         """Command Holly core to dump the page HTML"""
-        self.send(HollyMessage("", "", "<html>"))
+        self.command("html")
 
     def restart(self):
         """Command Holly core to restart"""
-        self.send(HollyMessage("", "", "<restart>"))
+        self.command("restart")
 
     def refresh(self):
         """Command Holly core to refresh the page"""
-        self.send(HollyMessage("", "", "<refresh>"))
+        self.command("refresh")
 
-    def file(self, path: str, chat_id: str):
+    def file(self, path: str, chat_id: str, request_id: str = None):
         """Sends a file into a chat"""
-        self.send(HollyMessage(path, chat_id, "<file>"))
\ No newline at end of file
+        req = {"type": "send_file", "chat_id": chat_id, "path": path}
+        if request_id is not None:
+            req["request_id"] = request_id
+        self.request(req)
@@ -0,0 +1,100 @@
+// Jackson Coxson
+// Reads a chat's older messages by scrolling it up, a page at a time.
+// Each page is sent to the child that asked as soon as it's read,
+// since scrolling back far enough can take minutes.
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde_json::{json, Value};
+use thirtyfour::error::WebDriverResult;
+
+use crate::{browser::Browser, cache, chat::ChatMessage, protocol::Event, queue::EventQueue};
+
+/// How many messages to read if the child doesn't say
+const DEFAULT_LIMIT: usize = 500;
+
+/// Scrolls in a row that turn up nothing new before we decide we're at the top
+const MAX_STALLS: usize = 3;
+
+/// Scrolls to give up after, no matter what
+const MAX_PAGES: usize = 200;
+
+/// Streams the chat's messages to `reply`, newest page first, until the limit or date is reached.
+/// Holds up the browser the whole time, which is why it takes the admin scope.
+/// Returns a summary for the ack.
+pub async fn run(
+    client: &Browser,
+    chat_id: &str,
+    limit: Option<usize>,
+    since: Option<DateTime<Utc>>,
+    reply: &EventQueue,
+    request_id: Option<String>,
+) -> WebDriverResult<Value> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT);
+    info!("Backfilling up to {limit} messages from {chat_id}");
+    client.go_to_chat(chat_id).await?;
+
+    let mut window = client.get_messages(false).await?;
+    let mut page = window.clone();
+    let mut total = 0;
+    let mut stalls = 0;
+    for _ in 0..MAX_PAGES {
+        // The oldest page usually runs past `since`, messages with no time are kept
+        if let Some(since) = since {
+            page.retain(|m| m.sent_at.is_none_or(|t| t >= since));
+        }
+        if !page.is_empty() {
+            stalls = 0;
+            // Past the limit, keep the newest of the page since it follows the last one
+            if total + page.len() > limit {
+                page.drain(..page.len() - (limit - total));
+            }
+            total += page.len();
+            let now = Utc::now();
+            for message in &mut page {
+                message.detected_at = Some(now);
+            }
+            reply.push_unbounded(Event::Backfill {
+                request_id: request_id.clone(),
+                chat_id: chat_id.to_string(),
+                messages: std::mem::take(&mut page),
+            });
+        } else {
+            stalls += 1;
+        }
+
+        if total >= limit || stalls >= MAX_STALLS {
+            break;
+        }
+        if let Some(since) = since {
+            if client
+                .get_time_separators()
+                .await?
+                .first()
+                .is_some_and(|t| *t < since)
+            {
+                info!("Backfill of {chat_id} reached {since}");
+                break;
+            }
+        }
+
+        client.scroll_to_oldest().await?;
+        let loaded = client.get_messages(false).await?;
+        page = older(&window, &loaded);
+        window = loaded;
+    }
+
+    client.scroll_to_newest().await?;
+    info!("Backfilled {total} messages from {chat_id}");
+    Ok(json!({ "messages": total, "reached_top": stalls >= MAX_STALLS }))
+}
+
+/// The messages loaded above the previous window.
+/// This is the cache's diff run backwards, since older messages appear at the top.
+fn older(window: &[ChatMessage], loaded: &[ChatMessage]) -> Vec<ChatMessage> {
+    let window: Vec<ChatMessage> = window.iter().rev().cloned().collect();
+    let loaded: Vec<ChatMessage> = loaded.iter().rev().cloned().collect();
+    let mut older = cache::unseen(&window, &loaded);
+    older.reverse();
+    older
+}
@@ -2,14 +2,30 @@
 
 use std::process::Stdio;
 
-use log::{error, info, warn};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::{Number, Value};
 use thirtyfour::prelude::*;
-use tokio::process::{Child, Command};
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, Command},
+};
 
-use crate::config::Config;
+use crate::{chat::ChatMessage, config::Config, protocol::MessageTarget};
+
+/// The scrolling grid of messages in the current chat
+const CONVERSATION_XPATH: &str = "//div[contains(@aria-label, 'conversation') and @role='grid']";
+
+/// Hosts Messenger serves attachments from. Our cookies are never sent anywhere else.
+const MEDIA_HOSTS: &[&str] = &["fbcdn.net", "fbsbx.com", "facebook.com", "messenger.com"];
+
+/// How long an attachment download can take before it's given up on
+const DOWNLOAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Attachments bigger than this aren't downloaded. Messenger caps uploads well below it.
+const MAX_DOWNLOAD_SIZE: u64 = 256 * 1024 * 1024;
 
 const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/87.0.4280.88 Safari/537.36";
 
@@ -19,6 +35,15 @@ pub struct Browser {
     _gecko: Child,
 }
 
+/// Messenger refused a file we tried to upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadError {
+    /// The "Invalid file format" dialog popped up
+    InvalidFormat,
+    /// The "Failed to upload files" dialog popped up
+    Failed,
+}
+
 #[derive(Serialize, Deserialize)]
 struct JsonCookie {
     name: String,
@@ -183,8 +208,8 @@ impl Browser {
         Ok(())
     }
 
-    /// Takes a screenshot and saves it to logs/timestamp.png
-    pub async fn screenshot_log(&self) -> WebDriverResult<()> {
+    /// Takes a screenshot and saves it to logs/timestamp.png, returning the path
+    pub async fn screenshot_log(&self) -> WebDriverResult<String> {
         let b = self.driver.screenshot_as_png().await?;
         let timestamp = chrono::offset::Local::now().to_string();
 
@@ -196,7 +221,8 @@ impl Browser {
             ));
         }
 
-        match tokio::fs::File::create(format!("logs/{timestamp}-log.png")).await {
+        let path = format!("logs/{timestamp}-log.png");
+        match tokio::fs::File::create(&path).await {
             Ok(mut file) => {
                 if tokio::io::AsyncWriteExt::write_all(&mut file, &b)
                     .await
@@ -207,7 +233,7 @@ impl Browser {
                         "Could not write screenshot data to file".to_string(),
                     ));
                 }
-                Ok(())
+                Ok(path)
             }
             Err(e) => {
                 error!("Could not create file to save screenshot: {:?}", e);
@@ -218,8 +244,8 @@ impl Browser {
         }
     }
 
-    /// Takes a snapshot of the page HTML and saves to to logs/timestamp.html
-    pub async fn html_log(&self) -> WebDriverResult<()> {
+    /// Takes a snapshot of the page HTML and saves to to logs/timestamp.html, returning the path
+    pub async fn html_log(&self) -> WebDriverResult<String> {
         let html = self.driver.source().await?;
         let timestamp = chrono::offset::Local::now().to_string();
 
@@ -231,7 +257,8 @@ impl Browser {
             ));
         }
 
-        if let Ok(mut file) = tokio::fs::File::create(format!("logs/{timestamp}-log.html")).await {
+        let path = format!("logs/{timestamp}-log.html");
+        if let Ok(mut file) = tokio::fs::File::create(&path).await {
             if tokio::io::AsyncWriteExt::write_all(&mut file, html.as_bytes())
                 .await
                 .is_err()
@@ -241,7 +268,7 @@ impl Browser {
                     "Could not write html data to file".to_string(),
                 ));
             }
-            Ok(())
+            Ok(path)
         } else {
             error!("Could not create file to save html");
             Err(WebDriverError::CustomError(
@@ -256,17 +283,334 @@ impl Browser {
         let id = current_url
             .path()
             .split('/')
-            .filter(|x| !x.is_empty())
-            .last()
+            .rfind(|x| !x.is_empty())
             .unwrap();
         Ok(id.to_string())
     }
 
+    /// Gets all the messages in the current chat, along with the row each is in
+    pub async fn get_messages_with_rows(&self) -> WebDriverResult<Vec<(WebElement, ChatMessage)>> {
+        ChatMessage::get_with_rows(&self.driver, self.get_current_chat().await?, false).await
+    }
+
     /// Gets the list of all the messages in the current chat
     pub async fn get_messages(&self, last: bool) -> WebDriverResult<Vec<crate::chat::ChatMessage>> {
         crate::chat::ChatMessage::get(&self.driver, self.get_current_chat().await?, last).await
     }
 
+    /// Reads when messages were sent from the tooltip Messenger shows while they're hovered.
+    /// Only messages with no separator above them, and so no time yet, are hovered.
+    /// `rows` is the sample the messages were found in.
+    pub async fn read_sent_times(
+        &self,
+        messages: &mut [ChatMessage],
+        rows: &[(WebElement, ChatMessage)],
+    ) -> WebDriverResult<()> {
+        for message in messages.iter_mut().filter(|m| m.sent_at.is_none()) {
+            let Some((row, _)) = rows.iter().rev().find(|(_, m)| m.same_message(message)) else {
+                continue;
+            };
+            if let Some(time) = self.hover_time(row).await? {
+                message.sent_at = Some(time);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hovers a message's bubble and reads the time off its tooltip
+    async fn hover_time(&self, row: &WebElement) -> WebDriverResult<Option<DateTime<Utc>>> {
+        let bubble = match row.find(By::XPath(".//div[@dir='auto']")).await {
+            Ok(b) => b,
+            Err(_) => row.clone(),
+        };
+        self.driver
+            .action_chain()
+            .move_to_element_center(&bubble)
+            .perform()
+            .await?;
+        tokio::time::sleep(std::time::Duration::from_millis(self.latency as u64)).await;
+
+        // The hovered element points at its tooltip, which may be rendered elsewhere on the page
+        let described = row
+            .find(By::XPath("descendant-or-self::*[@aria-describedby]"))
+            .await;
+        let tooltip = match described {
+            Ok(el) => match el.attr("aria-describedby").await? {
+                Some(id) if !id.contains('\'') => self
+                    .driver
+                    .find(By::XPath(&format!("//*[@id='{id}']")))
+                    .await
+                    .ok(),
+                _ => None,
+            },
+            Err(_) => row.find(By::XPath(".//div[@role='tooltip']")).await.ok(),
+        };
+        let Some(tooltip) = tooltip else {
+            return Ok(None);
+        };
+        let label = tooltip.text().await?;
+        let time = crate::timestamp::parse_label(&label);
+        if time.is_none() {
+            debug!("Unable to read a time from the tooltip {label:?}");
+        }
+        Ok(time)
+    }
+
+    /// The browser's cookies as a `Cookie` header, for fetching attachments like the page does
+    pub async fn cookie_header(&self) -> WebDriverResult<String> {
+        Ok(self
+            .driver
+            .get_all_cookies()
+            .await?
+            .iter()
+            .map(|c| format!("{}={}", c.name(), c.value()))
+            .collect::<Vec<_>>()
+            .join("; "))
+    }
+
+    /// Finds the row of the newest message on screen in the current chat that matches the target.
+    /// With `own`, only Holly's own messages are considered.
+    pub async fn find_message(
+        &self,
+        target: &MessageTarget,
+        own: bool,
+    ) -> WebDriverResult<Option<WebElement>> {
+        let rows =
+            ChatMessage::get_with_rows(&self.driver, self.get_current_chat().await?, false).await?;
+        Ok(rows
+            .into_iter()
+            .rev()
+            .find(|(_, m)| (!own || m.from_self) && target.matches(m))
+            .map(|(row, _)| row))
+    }
+
+    /// Hovers a message and clicks one of the buttons Messenger shows beside it, like "Reply".
+    /// Returns false if the button never showed up.
+    async fn hover_action(&self, row: &WebElement, label: &str) -> WebDriverResult<bool> {
+        row.scroll_into_view().await?;
+        self.driver
+            .action_chain()
+            .move_to_element_center(row)
+            .perform()
+            .await?;
+        tokio::time::sleep(std::time::Duration::from_millis(self.latency as u64)).await;
+
+        let xpath = format!("//div[@aria-label='{label}' and @role='button']");
+        let button = match row.find(By::XPath(&format!(".{xpath}"))).await {
+            Ok(b) => b,
+            // The toolbar isn't always inside the row, but only the hovered message has one
+            Err(_) => match self.driver.find(By::XPath(&xpath)).await {
+                Ok(b) => b,
+                Err(e) => {
+                    warn!("No {label} button on the hovered message: {e:?}");
+                    return Ok(false);
+                }
+            },
+        };
+        button.click().await?;
+        tokio::time::sleep(std::time::Duration::from_millis(self.latency as u64)).await;
+        Ok(true)
+    }
+
+    /// Puts the composer into reply mode for the target message in the current chat
+    pub async fn start_reply(&self, target: &MessageTarget) -> WebDriverResult<Result<(), String>> {
+        let Some(row) = self.find_message(target, false).await? else {
+            return Ok(Err("Unable to find the message to reply to".to_string()));
+        };
+        if !self.hover_action(&row, "Reply").await? {
+            return Ok(Err(
+                "Messenger didn't offer to reply to that message".to_string()
+            ));
+        }
+        Ok(Ok(()))
+    }
+
+    /// Reacts to the target message in the current chat with an emoji from the reaction bar
+    pub async fn react(
+        &self,
+        target: &MessageTarget,
+        emoji: &str,
+    ) -> WebDriverResult<Result<(), String>> {
+        if emoji.is_empty() || emoji.contains('\'') {
+            return Ok(Err(format!("{emoji:?} isn't an emoji")));
+        }
+        let Some(row) = self.find_message(target, false).await? else {
+            return Ok(Err("Unable to find the message to react to".to_string()));
+        };
+        if !self.hover_action(&row, "React").await? {
+            return Ok(Err(
+                "Messenger didn't offer to react to that message".to_string()
+            ));
+        }
+
+        // The bar labels its emoji with or without the variation selector, depending on the emoji
+        let plain: String = emoji.chars().filter(|&c| c != '\u{fe0f}').collect();
+        for e in [emoji, plain.as_str()] {
+            let xpath = format!(
+                "//div[(@role='button' or @role='menuitem') and (@aria-label='{e}' or .//img[@alt='{e}'])]"
+            );
+            if let Ok(button) = self.driver.find(By::XPath(&xpath)).await {
+                button.click().await?;
+                tokio::time::sleep(std::time::Duration::from_millis(self.latency as u64)).await;
+                return Ok(Ok(()));
+            }
+        }
+        self.dismiss().await?;
+        Ok(Err(format!("{emoji} isn't in Messenger's reaction bar")))
+    }
+
+    /// Opens the "More" menu on a message and clicks one of its items, like "Edit".
+    /// Returns false, with the menu closed again, if the item isn't there.
+    async fn more_action(&self, row: &WebElement, item: &str) -> WebDriverResult<bool> {
+        if !self.hover_action(row, "More").await? {
+            return Ok(false);
+        }
+        let xpath = format!("//div[@role='menuitem' and .//span[text()='{item}']]");
+        match self.driver.find(By::XPath(&xpath)).await {
+            Ok(menu_item) => {
+                menu_item.click().await?;
+                tokio::time::sleep(std::time::Duration::from_millis(self.latency as u64)).await;
+                Ok(true)
+            }
+            Err(e) => {
+                warn!("No {item} in the message's menu: {e:?}");
+                self.dismiss().await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Closes whatever menu or bar is open, so it isn't in the way of the next message
+    async fn dismiss(&self) -> WebDriverResult<()> {
+        self.driver
+            .action_chain()
+            .send_keys(Key::Escape + "")
+            .perform()
+            .await
+    }
+
+    /// Replaces the text of one of Holly's own messages in the current chat.
+    /// Messenger only offers this for a while after the message was sent.
+    pub async fn edit_message(
+        &self,
+        target: &MessageTarget,
+        content: &str,
+    ) -> WebDriverResult<Result<(), String>> {
+        let Some(row) = self.find_message(target, true).await? else {
+            return Ok(Err("Unable to find Holly's message to edit".to_string()));
+        };
+        if !self.more_action(&row, "Edit").await? {
+            return Ok(Err(
+                "Messenger didn't offer to edit that message, only Holly's own recent messages can be"
+                    .to_string(),
+            ));
+        }
+
+        // The composer now holds the old text, which the new text replaces
+        let chat_bar = self
+            .driver
+            .find(By::XPath("//div[@role='textbox']"))
+            .await?;
+        chat_bar.send_keys(Key::Control + "a").await?;
+        chat_bar.send_keys(Key::Backspace + "").await?;
+        self.send_message(content).await?;
+        Ok(Ok(()))
+    }
+
+    /// Removes one of Holly's own messages in the current chat for everyone
+    pub async fn unsend_message(
+        &self,
+        target: &MessageTarget,
+    ) -> WebDriverResult<Result<(), String>> {
+        let Some(row) = self.find_message(target, true).await? else {
+            return Ok(Err("Unable to find Holly's message to unsend".to_string()));
+        };
+        if !self.more_action(&row, "Unsend").await? {
+            return Ok(Err(
+                "Messenger didn't offer to unsend that message, only Holly's own messages can be"
+                    .to_string(),
+            ));
+        }
+
+        // Messenger asks who to unsend it for, everyone is picked unless told otherwise
+        if let Ok(everyone) = self
+            .driver
+            .find(By::XPath(
+                "//div[@role='dialog']//*[@role='radio' and .//span[text()='Unsend for everyone']]",
+            ))
+            .await
+        {
+            everyone.click().await?;
+        }
+        let remove = match self
+            .driver
+            .query(By::XPath(
+                "//div[@role='dialog']//div[@role='button' and (@aria-label='Remove' or .//span[text()='Remove'])]",
+            ))
+            .wait(
+                std::time::Duration::from_secs(2),
+                std::time::Duration::from_millis(100),
+            )
+            .first()
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("No confirmation to unsend the message: {e:?}");
+                self.dismiss().await?;
+                return Ok(Err(
+                    "Messenger didn't ask to confirm unsending the message".to_string()
+                ));
+            }
+        };
+        remove.click().await?;
+        tokio::time::sleep(std::time::Duration::from_millis(self.latency as u64)).await;
+        Ok(Ok(()))
+    }
+
+    /// Scrolls the current chat to its oldest loaded message, so Messenger loads older ones
+    pub async fn scroll_to_oldest(&self) -> WebDriverResult<()> {
+        if let Some(row) = self.message_rows().await?.first() {
+            row.scroll_into_view().await?;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(self.latency as u64)).await;
+        Ok(())
+    }
+
+    /// Scrolls the current chat back down to the newest message
+    pub async fn scroll_to_newest(&self) -> WebDriverResult<()> {
+        if let Some(row) = self.message_rows().await?.last() {
+            row.scroll_into_view().await?;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(self.latency as u64)).await;
+        Ok(())
+    }
+
+    async fn message_rows(&self) -> WebDriverResult<Vec<WebElement>> {
+        self.driver
+            .find(By::XPath(CONVERSATION_XPATH))
+            .await?
+            .find_all(By::XPath(".//div[@class='x78zum5 xdt5ytf']"))
+            .await
+    }
+
+    /// The times on the separators between messages loaded in the current chat, oldest first
+    pub async fn get_time_separators(&self) -> WebDriverResult<Vec<DateTime<Utc>>> {
+        let headings = self
+            .driver
+            .find(By::XPath(CONVERSATION_XPATH))
+            .await?
+            .find_all(By::XPath(".//h4"))
+            .await?;
+        let mut times = Vec::new();
+        for heading in headings {
+            if let Some(time) = crate::timestamp::parse_label(&heading.text().await?) {
+                times.push(time);
+            }
+        }
+        Ok(times)
+    }
+
     /// Sends a message to the current chat
     pub async fn send_message(&self, message: &str) -> WebDriverResult<()> {
         self.decline_call().await.unwrap();
@@ -330,7 +674,9 @@ impl Browser {
         Ok(())
     }
 
-    pub async fn send_file(&self, path: &str) -> WebDriverResult<()> {
+    /// Uploads a file into the current chat.
+    /// The outer result is for browser failures, the inner one for files Messenger refused.
+    pub async fn send_file(&self, path: &str) -> WebDriverResult<Result<(), UploadError>> {
         self.decline_call().await.unwrap();
 
         let chat_bar = match self
@@ -381,6 +727,7 @@ impl Browser {
                 .await?
                 .click()
                 .await?;
+            return Ok(Err(UploadError::InvalidFormat));
         }
 
         // Detect a file upload
@@ -396,6 +743,7 @@ impl Browser {
                 .await?
                 .click()
                 .await?;
+            return Ok(Err(UploadError::Failed));
         }
 
         chat_bar.click().await?;
@@ -409,7 +757,7 @@ impl Browser {
             let _ = send_button.click().await;
         }
 
-        Ok(())
+        Ok(Ok(()))
     }
 
     /// Dumps the cookies to cookies.json so we don't have to login every time
@@ -469,6 +817,98 @@ impl Browser {
     }
 }
 
+/// Fetches a Messenger attachment with the browser's cookies and saves it to `dir`, returning where.
+/// Doesn't need the browser, so it can run while Holly gets on with reading and sending.
+pub async fn download_attachment(url: &str, dir: &str, cookies: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(url).map_err(|e| format!("Invalid URL: {e}"))?;
+    let host = url.host_str().unwrap_or_default();
+    if url.scheme() != "https"
+        || !MEDIA_HOSTS
+            .iter()
+            .any(|h| host == *h || host.ends_with(&format!(".{h}")))
+    {
+        return Err(format!("Not a Messenger attachment: {url}"));
+    }
+
+    let mut response = reqwest::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Unable to create HTTP client: {e}"))?
+        .get(url.clone())
+        .header(reqwest::header::COOKIE, cookies)
+        .header(reqwest::header::USER_AGENT, USER_AGENT)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("Unable to download {url}: {e}"))?;
+    if response
+        .content_length()
+        .is_some_and(|len| len > MAX_DOWNLOAD_SIZE)
+    {
+        return Err(format!(
+            "{url} is larger than the {MAX_DOWNLOAD_SIZE} byte limit"
+        ));
+    }
+
+    let name: String = url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        .collect();
+    let name = if name.is_empty() {
+        "attachment".to_string()
+    } else {
+        name
+    };
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| format!("Unable to create {dir}: {e}"))?;
+    let path = format!("{dir}/{}-{name}", chrono::Utc::now().timestamp_millis());
+
+    // Written as it arrives, so a big video is never held in memory
+    let write = async {
+        let mut file = tokio::fs::File::create(&path)
+            .await
+            .map_err(|e| format!("Unable to write {path}: {e}"))?;
+        let mut written = 0;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| format!("Unable to download {url}: {e}"))?
+        {
+            written += chunk.len() as u64;
+            if written > MAX_DOWNLOAD_SIZE {
+                return Err(format!(
+                    "{url} is larger than the {MAX_DOWNLOAD_SIZE} byte limit"
+                ));
+            }
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| format!("Unable to write {path}: {e}"))?;
+        }
+        file.flush()
+            .await
+            .map_err(|e| format!("Unable to write {path}: {e}"))
+    };
+    if let Err(e) = write.await {
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(e);
+    }
+    info!("Downloaded {url} to {path}");
+    Ok(path)
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::InvalidFormat => write!(f, "Invalid file format"),
+            UploadError::Failed => write!(f, "Failed to upload file (is it below 25 MB?)"),
+        }
+    }
+}
+
 fn launch_driver(path: &str, port: u16) -> Child {
     Command::new(path)
         .arg("-p")
@@ -4,46 +4,66 @@
 // As of writing, the way to compare messages was to compare the content and sender.
 // This meant that if a person sent the same message twice, it was ignored.
 // Facebook ships roughly 13 messages on load, which means we can compare a tree.
+// The cache is saved to disk, so messages that arrive while Holly restarts are still noticed.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, io::Write};
 
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 
 use crate::chat::ChatMessage;
 
+/// What changed in a chat since it was last checked
+#[derive(Default)]
+pub struct Changes {
+    /// Messages that weren't in the window before
+    pub messages: Vec<ChatMessage>,
+    /// Messages already seen whose reactions changed
+    pub reactions: Vec<ChatMessage>,
+}
+
 pub struct Cache {
     inner: HashMap<String, Vec<ChatMessage>>,
+    path: String,
 }
 
 impl Cache {
-    pub fn new() -> Self {
+    /// Loads the cache from disk, or starts an empty one
+    pub fn load(path: &str) -> Self {
+        let inner = match std::fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(i) => i,
+                Err(e) => {
+                    error!("Cache at {path} is corrupt, starting over: {e:?}");
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+        info!("Loaded {} chats from the cache", inner.len());
         Self {
-            inner: HashMap::new(),
+            inner,
+            path: path.to_string(),
         }
     }
 
-    pub async fn check(
-        &mut self,
-        chat_id: &str,
-        new_messages: &Vec<ChatMessage>,
-    ) -> Option<Vec<ChatMessage>> {
+    pub async fn check(&mut self, chat_id: &str, new_messages: &Vec<ChatMessage>) -> Changes {
         let old_messages = match self.inner.get(chat_id) {
             Some(o) => o,
             None => {
                 info!("Inserting new chat into cache: {:?}", chat_id);
-                self.inner.insert(chat_id.to_owned(), new_messages.clone());
-                return None;
+                self.insert(chat_id, new_messages);
+                return Changes::default();
             }
         };
 
         if old_messages.is_empty() {
             warn!("Cache for {chat_id} was empty");
-            self.inner.insert(chat_id.to_owned(), new_messages.clone());
-            return None;
+            self.insert(chat_id, new_messages);
+            return Changes::default();
         }
         if new_messages.is_empty() {
             warn!("Comparing against empty new messages");
-            return None;
+            return Changes::default();
         }
 
         let debug_lines = new_messages.iter().zip(old_messages.iter());
@@ -53,36 +73,38 @@ impl Cache {
         }
 
         if new_messages == old_messages {
-            return None;
+            return Changes::default();
         }
 
-        let mut new_count = 0;
-        let mut old_count = 0;
-        let last_old_message = old_messages.last().cloned();
-        let last_new_message = new_messages.last().cloned();
-        loop {
-            if old_messages[old_count] == new_messages[new_count] {
-                new_count += 1;
-            }
-            old_count += 1;
-
-            if old_count == old_messages.len() {
-                self.inner.insert(chat_id.to_owned(), new_messages.clone());
-                if new_count > 3 {
-                    return Some(new_messages[new_count..].to_vec());
-                } else {
-                    warn!("New messages had no match on old messages");
-                    // We'll just return a single latest message since the caches don't match up
-                    if last_old_message != last_new_message {
-                        return Some(vec![last_new_message?]);
-                    }
-                    return None;
+        let unread = unseen(old_messages, new_messages);
+        if unread.len() == new_messages.len() {
+            warn!("New messages had no match on old messages, treating them all as new");
+        }
+        let reactions = reacted(old_messages, new_messages);
+        self.insert(chat_id, new_messages);
+        Changes {
+            messages: unread,
+            reactions,
+        }
+    }
+
+    fn insert(&mut self, chat_id: &str, messages: &[ChatMessage]) {
+        self.inner.insert(chat_id.to_owned(), messages.to_vec());
+        match serde_json::to_vec(&self.inner) {
+            Ok(b) => {
+                // Written beside it and moved over it, so a crash mid-write can't leave it torn
+                let tmp = format!("{}.tmp", self.path);
+                let written = std::fs::File::create(&tmp)
+                    .and_then(|mut f| {
+                        f.write_all(&b)?;
+                        f.sync_all()
+                    })
+                    .and_then(|()| std::fs::rename(&tmp, &self.path));
+                if let Err(e) = written {
+                    error!("Unable to save the cache to {}: {e:?}", self.path);
                 }
             }
-            if new_count == new_messages.len() {
-                debug!("The new message length was somehow shorter?");
-                return None;
-            }
+            Err(e) => error!("Unable to serialize the cache: {e:?}"),
         }
     }
 
@@ -94,3 +116,204 @@ impl Cache {
         self.inner.len()
     }
 }
+
+/// Aligns the two windows on their longest common subsequence and returns the messages after the last match.
+/// Messages with IDs from the page are matched on those, so repeats of the same text are never confused.
+/// Messages only arrive at the bottom, so anything unmatched above that is an older message or a deletion.
+/// Matches are placed as early in the new window as possible, so a repeated message counts as new
+/// rather than being matched against its older copy.
+pub fn unseen(old: &[ChatMessage], new: &[ChatMessage]) -> Vec<ChatMessage> {
+    match align(old, new).last() {
+        Some((_, j)) => new[j + 1..].to_vec(),
+        None => new.to_vec(),
+    }
+}
+
+/// Messages present in both windows whose reactions changed, as they are now
+pub fn reacted(old: &[ChatMessage], new: &[ChatMessage]) -> Vec<ChatMessage> {
+    align(old, new)
+        .into_iter()
+        .filter(|(i, j)| old[*i].reactions != new[*j].reactions)
+        .map(|(_, j)| new[j].clone())
+        .collect()
+}
+
+/// Pairs of indices into old and new that are the same message, in order
+fn align(old: &[ChatMessage], new: &[ChatMessage]) -> Vec<(usize, usize)> {
+    // lcs[i][j] is the length of the LCS of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i].same_message(&new[j]) {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut matches = Vec::new();
+    while i < old.len() && j < new.len() {
+        if old[i].same_message(&new[j]) && lcs[i][j] == lcs[i + 1][j + 1] + 1 {
+            matches.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] == lcs[i][j] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(messages: &[&str]) -> Vec<ChatMessage> {
+        messages
+            .iter()
+            .map(|m| ChatMessage {
+                sender: "Holly".to_string(),
+                content: m.to_string(),
+                chat_id: "1".to_string(),
+                message_id: None,
+                reply_to: None,
+                attachments: Vec::new(),
+                reactions: Vec::new(),
+                from_self: false,
+                sent_at: None,
+                detected_at: None,
+            })
+            .collect()
+    }
+
+    fn with_ids(messages: &[(&str, &str)]) -> Vec<ChatMessage> {
+        messages
+            .iter()
+            .map(|(id, m)| ChatMessage {
+                sender: "Holly".to_string(),
+                content: m.to_string(),
+                chat_id: "1".to_string(),
+                message_id: Some(id.to_string()),
+                reply_to: None,
+                attachments: Vec::new(),
+                reactions: Vec::new(),
+                from_self: false,
+                sent_at: None,
+                detected_at: None,
+            })
+            .collect()
+    }
+
+    fn contents(messages: Vec<ChatMessage>) -> Vec<String> {
+        messages.into_iter().map(|m| m.content).collect()
+    }
+
+    #[test]
+    fn unchanged() {
+        let w = window(&["a", "b", "c"]);
+        assert!(unseen(&w, &w).is_empty());
+    }
+
+    #[test]
+    fn appended() {
+        let old = window(&["a", "b", "c"]);
+        let new = window(&["a", "b", "c", "d", "e"]);
+        assert_eq!(contents(unseen(&old, &new)), ["d", "e"]);
+    }
+
+    #[test]
+    fn window_shifted() {
+        let old = window(&["a", "b", "c", "d"]);
+        let new = window(&["c", "d", "e", "f"]);
+        assert_eq!(contents(unseen(&old, &new)), ["e", "f"]);
+    }
+
+    #[test]
+    fn more_than_a_window() {
+        let old = window(&["a", "b", "c"]);
+        let new = window(&["d", "e", "f"]);
+        assert_eq!(contents(unseen(&old, &new)), ["d", "e", "f"]);
+    }
+
+    #[test]
+    fn repeated_message() {
+        let old = window(&["a", "x"]);
+        let new = window(&["a", "x", "x"]);
+        assert_eq!(contents(unseen(&old, &new)), ["x"]);
+    }
+
+    #[test]
+    fn repeated_message_with_shift() {
+        let old = window(&["a", "x", "x"]);
+        let new = window(&["x", "x", "x"]);
+        assert_eq!(contents(unseen(&old, &new)), ["x"]);
+    }
+
+    #[test]
+    fn older_messages_loaded() {
+        let old = window(&["d", "e", "f"]);
+        let new = window(&["a", "b", "c", "d", "e", "f"]);
+        assert!(unseen(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn deleted() {
+        let old = window(&["a", "b", "c", "d"]);
+        let new = window(&["a", "c", "d"]);
+        assert!(unseen(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn deleted_and_appended() {
+        let old = window(&["a", "b", "c", "d"]);
+        let new = window(&["a", "c", "d", "e"]);
+        assert_eq!(contents(unseen(&old, &new)), ["e"]);
+    }
+
+    #[test]
+    fn repeated_message_with_ids() {
+        let old = with_ids(&[("1", "x"), ("2", "x")]);
+        let new = with_ids(&[("2", "x"), ("3", "x"), ("4", "x")]);
+        assert_eq!(contents(unseen(&old, &new)), ["x", "x"]);
+    }
+
+    #[test]
+    fn edited_message_with_id() {
+        let old = with_ids(&[("1", "a"), ("2", "b")]);
+        let new = with_ids(&[("1", "a"), ("2", "b (edited)")]);
+        assert!(unseen(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn last_message_deleted() {
+        let old = window(&["a", "b", "c"]);
+        let new = window(&["a", "b"]);
+        assert!(unseen(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn reaction_added() {
+        let old = with_ids(&[("1", "a"), ("2", "b"), ("3", "c")]);
+        let mut new = old.clone();
+        new[1].reactions = vec!["👍".to_string()];
+        new.push(with_ids(&[("4", "d")]).remove(0));
+        assert_eq!(contents(unseen(&old, &new)), ["d"]);
+        let changed = reacted(&old, &new);
+        assert_eq!(contents(changed.clone()), ["b"]);
+        assert_eq!(changed[0].reactions, ["👍"]);
+    }
+
+    #[test]
+    fn reaction_removed_after_shift() {
+        let mut old = window(&["a", "b", "c"]);
+        old[2].reactions = vec!["❤".to_string(), "😆".to_string()];
+        let mut new = window(&["b", "c", "d"]);
+        new[1].reactions = vec!["❤".to_string()];
+        assert_eq!(contents(reacted(&old, &new)), ["c"]);
+        assert!(reacted(&new, &new).is_empty());
+    }
+}
@@ -5,10 +5,14 @@ use std::{
     time::Duration,
 };
 
+use chrono::{DateTime, Utc};
 use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 use thirtyfour::prelude::*;
 
+/// The sender given to messages Holly sent herself
+pub const SELF_SENDER: &str = "Holly";
+
 /// A chat found on the sidebar.
 /// Includes whether or not the chat is unread.
 pub struct ChatOption {
@@ -23,8 +27,67 @@ pub struct ChatMessage {
     pub sender: String,
     pub content: String,
     pub chat_id: String,
+    /// Messenger's own ID for the message, when the page has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// The message this one replied to, if it was a reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ReplyTo>,
+    /// Photos, videos, voice clips and files sent with the message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// Emoji people have reacted with, once each, in the order Messenger shows them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<String>,
+    /// Whether Holly sent this herself. Her messages are sent by `Holly`, whatever the account is called.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub from_self: bool,
+    /// When the message was sent, going by the time separator above it.
+    /// Holly fills in the time she noticed it if the page didn't say.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime<Utc>>,
+    /// When Holly noticed the message, set as it's delivered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detected_at: Option<DateTime<Utc>>,
+}
+
+/// The quoted message above a reply
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplyTo {
+    /// Who sent the quoted message, as Messenger names them. Replies to Holly say "you".
+    pub sender: String,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
 }
 
+/// Media or a file sent with a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub kind: AttachmentKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// Where the page loads it from. Pass it to `download_attachment` to save it.
+    pub url: String,
+    /// Pixel size of photos and videos
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentKind {
+    Image,
+    Video,
+    Audio,
+    File,
+}
+
+/// Photos smaller than this are avatars and read receipts, not attachments
+const MIN_IMAGE_SIZE: u32 = 64;
+
 impl ChatOption {
     /// Gets all the chats in the sidebar
     pub async fn get_all(driver: &WebDriver) -> WebDriverResult<Vec<ChatOption>> {
@@ -84,6 +147,19 @@ impl ChatMessage {
         chat_id: String,
         last: bool,
     ) -> WebDriverResult<Vec<Self>> {
+        Ok(Self::get_with_rows(driver, chat_id, last)
+            .await?
+            .into_iter()
+            .map(|(_, m)| m)
+            .collect())
+    }
+
+    /// Gets all the chat messages in the current chat, along with the row each is in
+    pub async fn get_with_rows(
+        driver: &WebDriver,
+        chat_id: String,
+        last: bool,
+    ) -> WebDriverResult<Vec<(WebElement, Self)>> {
         // Get the chat container
         let chat_container = driver
             .query(By::XPath(
@@ -115,9 +191,15 @@ impl ChatMessage {
         }
 
         let mut res = Vec::new();
-        let mut homeless = Vec::new();
+        let mut homeless: Vec<(WebElement, Self)> = Vec::new();
+        let mut sent_at = None;
         for message in messages {
-            match message
+            // Time separators label the messages below them
+            if let Some(time) = separator_time(&message).await {
+                sent_at = Some(time);
+            }
+
+            let content = match message
                 .query(By::XPath(
                     ".//div[@class='html-div xexx8yu x4uap5 x18d9i69 xkhd6sd x1gslohp x11i5rnm x12nagc x1mh8g0r x1yc453h x126k92a x18lvrbx']",
                 ))
@@ -125,89 +207,255 @@ impl ChatMessage {
                 .first()
                 .await
             {
-                Ok(c) => {
-                    let content = c.text().await?;
-                    
-                    let sender = match message.query(By::XPath(".//img[@class='x1rg5ohu x5yr21d xl1xv1r xh8yej3']"))
-                    .wait(Duration::from_millis(15), Duration::from_millis(5))
-                    .first().await {
-                        Ok(c) => c.attr("alt").await?.unwrap(),
-                        Err(e) => {
-                            // If the same user sends a message twice in a row,
-                            // there will be no sender detected in the HTML.
-                            // Store the messages in the homeless camp until we get one.
-                            homeless.push(content);
-                            debug!("Unable to get sender from the image alt: {e:?}");
-                            continue;
-                        },
-                    };
-
-                    // We have a sender for the homeless messages
-                    for h in homeless.drain(..) {
-                        res.push(Self {
-                            sender: sender.clone(),
-                            content: h,
-                            chat_id: chat_id.clone()
-                        })
-                    }
-
-                    res.push(Self {
-                        sender,
-                        content,
-                        chat_id: chat_id.clone(),
-                    });
-                }
+                Ok(c) => Some(c.text().await?),
                 Err(e) => {
                     // Check if the message is a single emoji
                     debug!("Unable to get message from the element! {e:?}");
-
-                        match message.query(By::XPath(".//img[@class='xz74otr']")).wait(Duration::from_millis(15), Duration::from_millis(5)).first().await {
-                            Ok(o) => {
-                                if let Ok(Some(attr)) = o.attr("alt").await {
-                                    let content = attr.chars().filter(|&c| c != '\u{fe0f}').collect();
-                                    let sender = match message.query(By::XPath(".//img[@class='x1rg5ohu x5yr21d xl1xv1r xh8yej3']"))
-                                    .wait(Duration::from_millis(15), Duration::from_millis(5))
-                                    .first().await {
-                                        Ok(c) => c.attr("alt").await?.unwrap(),
-                                        Err(e) => {
-                                            homeless.push(content);
-                                            debug!("Unable to get sender from the image alt: {e:?}");
-                                            continue;
-                                        },
-                                    };
-                                    for h in homeless.drain(..) {
-                                        res.push(Self {
-                                            sender: sender.clone(),
-                                            content: h,
-                                            chat_id: chat_id.clone()
-                                        })
-                                    }
-                                    res.push(Self {
-                                        sender,
-                                        content,
-                                        chat_id: chat_id.clone(),
-                                    });
-                                } else {
-                                    debug!("Emoji object has no attribute");
-                                }
-                            }
-                            Err(e) => {
-                                debug!("No emoji object on message: {e:?}");
+                    match message.query(By::XPath(".//img[@class='xz74otr']")).wait(Duration::from_millis(15), Duration::from_millis(5)).first().await {
+                        Ok(o) => match o.attr("alt").await {
+                            Ok(Some(attr)) => Some(attr.chars().filter(|&c| c != '\u{fe0f}').collect()),
+                            _ => {
+                                debug!("Emoji object has no attribute");
+                                None
                             }
+                        },
+                        Err(e) => {
+                            debug!("No emoji object on message: {e:?}");
+                            None
                         }
+                    }
+                }
+            };
+            let attachments = Attachment::get_all(&message).await?;
+            if content.is_none() && attachments.is_empty() {
+                continue;
+            }
+            let found = Self {
+                sender: String::new(),
+                content: content.unwrap_or_default(),
+                chat_id: chat_id.clone(),
+                message_id: message_id(&message).await,
+                reply_to: ReplyTo::get(&message).await,
+                attachments,
+                reactions: reactions(&message).await?,
+                from_self: false,
+                sent_at,
+                detected_at: None,
+            };
 
-                    continue;
+            // Messenger labels Holly's own messages and gives them no avatar
+            if is_own(&message).await {
+                // Someone else's run of messages always ends on their avatar,
+                // so anything waiting for one before Holly's message is hers too
+                for (row, h) in homeless.drain(..) {
+                    res.push((row, h.sent_by_holly()));
                 }
+                res.push((message, found.sent_by_holly()));
+                continue;
+            }
+
+            let sender = match message.query(By::XPath(".//img[@class='x1rg5ohu x5yr21d xl1xv1r xh8yej3']"))
+            .wait(Duration::from_millis(15), Duration::from_millis(5))
+            .first().await {
+                Ok(c) => c.attr("alt").await?.unwrap(),
+                Err(e) => {
+                    // If the same user sends a message twice in a row,
+                    // there will be no sender detected in the HTML.
+                    // Store the messages in the homeless camp until we get one.
+                    homeless.push((message, found));
+                    debug!("Unable to get sender from the image alt: {e:?}");
+                    continue;
+                },
             };
+
+            // We have a sender for the homeless messages
+            for (row, h) in homeless.drain(..) {
+                res.push((row, Self {
+                    sender: sender.clone(),
+                    ..h
+                }))
+            }
+            res.push((message, Self { sender, ..found }));
+        }
+
+        // Nobody's avatar came after these, so they're Holly's
+        for (row, h) in homeless {
+            res.push((row, h.sent_by_holly()));
         }
 
         Ok(res)
     }
 
-    /// Removes special characters that can't be sent into Messenger
-    pub fn clean(&mut self) {
-        self.content = unidecode::unidecode(&self.content);
+    /// Marks a message as one Holly sent
+    fn sent_by_holly(self) -> Self {
+        Self {
+            sender: SELF_SENDER.to_string(),
+            from_self: true,
+            ..self
+        }
+    }
+
+    /// Whether this is the same message as another, even if it's been edited.
+    /// Without IDs from the page, identical messages can't be told apart.
+    pub fn same_message(&self, other: &Self) -> bool {
+        match (&self.message_id, &other.message_id) {
+            (Some(a), Some(b)) => a == b,
+            _ => {
+                self.sender == other.sender
+                    && self.content == other.content
+                    && self.chat_id == other.chat_id
+            }
+        }
+    }
+}
+
+impl Attachment {
+    /// Finds everything attached to the message in a row
+    pub async fn get_all(row: &WebElement) -> WebDriverResult<Vec<Self>> {
+        let elements = row
+            .find_all(By::XPath(
+                ".//img[contains(@src, 'scontent') and not(@class='x1rg5ohu x5yr21d xl1xv1r xh8yej3') and not(@class='xz74otr')] \
+                | .//video | .//audio | .//a[contains(@href, 'fbsbx.com')]",
+            ))
+            .await?;
+
+        let mut attachments = Vec::new();
+        for element in elements {
+            let attachment = match element.tag_name().await?.as_str() {
+                "img" => {
+                    let width = dimension(&element, "naturalWidth").await;
+                    let height = dimension(&element, "naturalHeight").await;
+                    if width.is_some_and(|w| w < MIN_IMAGE_SIZE) || height.is_some_and(|h| h < MIN_IMAGE_SIZE) {
+                        continue;
+                    }
+                    element.attr("src").await?.map(|url| Self {
+                        kind: AttachmentKind::Image,
+                        filename: None,
+                        url,
+                        width,
+                        height,
+                    })
+                }
+                "video" => {
+                    let width = dimension(&element, "videoWidth").await;
+                    let height = dimension(&element, "videoHeight").await;
+                    element.prop("currentSrc").await?.filter(|u| !u.is_empty()).map(|url| Self {
+                        kind: AttachmentKind::Video,
+                        filename: None,
+                        url,
+                        width,
+                        height,
+                    })
+                }
+                "audio" => element.prop("currentSrc").await?.filter(|u| !u.is_empty()).map(|url| Self {
+                    kind: AttachmentKind::Audio,
+                    filename: None,
+                    url,
+                    width: None,
+                    height: None,
+                }),
+                _ => {
+                    let filename = element.text().await?.trim().to_string();
+                    element.attr("href").await?.map(|url| Self {
+                        kind: AttachmentKind::File,
+                        filename: (!filename.is_empty()).then_some(filename),
+                        url,
+                        width: None,
+                        height: None,
+                    })
+                }
+            };
+            attachments.extend(attachment);
+        }
+        Ok(attachments)
+    }
+}
+
+impl ReplyTo {
+    /// Reads the quoted message above a reply, if the row is one.
+    /// Messenger heads it with "Alice replied to Bob" and renders the quote as a button that jumps to it.
+    pub async fn get(row: &WebElement) -> Option<Self> {
+        let header = row
+            .find(By::XPath(".//span[contains(text(), ' replied to ')]"))
+            .await
+            .ok()?
+            .text()
+            .await
+            .ok()?;
+        let (replier, quoted) = header.split_once(" replied to ")?;
+        let sender = match quoted {
+            "themself" | "themselves" | "herself" | "himself" => replier,
+            _ => quoted,
+        };
+
+        let quote = row.find(By::XPath(".//div[@role='button']")).await.ok()?;
+        let content = quote
+            .find(By::XPath(".//div[@dir='auto']"))
+            .await
+            .ok()?
+            .text()
+            .await
+            .ok()?;
+        Some(Self {
+            sender: sender.to_string(),
+            content,
+            message_id: message_id(&quote).await,
+        })
+    }
+}
+
+/// A numeric DOM property, like an image's natural width
+async fn dimension(element: &WebElement, prop: &str) -> Option<u32> {
+    element.prop(prop).await.ok()??.parse().ok()
+}
+
+/// The time on a row's separator, if it has one
+async fn separator_time(row: &WebElement) -> Option<DateTime<Utc>> {
+    let heading = row.find(By::XPath(".//h4")).await.ok()?;
+    crate::timestamp::parse_label(&heading.text().await.ok()?)
+}
+
+/// Whether the row is a message Holly sent, going by the label Messenger reads out for it
+async fn is_own(row: &WebElement) -> bool {
+    row.find(By::XPath(".//*[normalize-space(text())='You sent']"))
+        .await
+        .is_ok()
+}
+
+/// Messenger's ID for the message in a row, if the page has one
+async fn message_id(row: &WebElement) -> Option<String> {
+    let element = row
+        .find(By::XPath(
+            "descendant-or-self::*[@data-message-id or @data-mid]",
+        ))
+        .await
+        .ok()?;
+    for attr in ["data-message-id", "data-mid"] {
+        if let Ok(Some(id)) = element.attr(attr).await {
+            return Some(id);
+        }
+    }
+    None
+}
+
+/// The emoji in the reaction summary under a row. Messenger shows each emoji once,
+/// however many people used it, with the names behind a click.
+async fn reactions(row: &WebElement) -> WebDriverResult<Vec<String>> {
+    let emoji = row
+        .find_all(By::XPath(
+            ".//div[@role='button' and contains(@aria-label, 'reaction')]//img[@alt]",
+        ))
+        .await?;
+    let mut reactions = Vec::new();
+    for e in emoji {
+        if let Some(alt) = e.attr("alt").await? {
+            let alt: String = alt.chars().filter(|&c| c != '\u{fe0f}').collect();
+            if !alt.is_empty() && !reactions.contains(&alt) {
+                reactions.push(alt);
+            }
+        }
     }
+    Ok(reactions)
 }
 
 impl Debug for ChatOption {
@@ -231,6 +479,10 @@ impl Debug for ChatMessage {
             .field("sdr", &self.sender)
             .field("msg", &msg)
             .field("chat_id", &self.chat_id)
+            .field("id", &self.message_id)
+            .field("sent_at", &self.sent_at)
+            .field("reactions", &self.reactions)
+            .field("from_self", &self.from_self)
             .finish()
     }
 }
@@ -1,8 +1,16 @@
 // Jackson Coxson
 
+use std::collections::HashMap;
+
 use dialoguer::{theme::ColorfulTheme, Input, Password, Select};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    protocol::{Framing, Scope},
+    queue::Overflow,
+    ratelimit::OnLimit,
+};
+
 const DEFAULT_CONFIG: &str = r#"# Holly Config
 fb_username = "asdfasdf@urmom.com"
 fb_password = "monkey123"
@@ -16,6 +24,42 @@ headless = true
 [tcp]
 port = 8011
 host = "127.0.0.1"
+framing = "ndjson"
+
+# Uncomment to also listen on a Unix domain socket
+# [unix]
+# path = "/tmp/holly.sock"
+# mode = 0o660
+
+# Uncomment to also listen for WebSocket connections
+# [websocket]
+# port = 8012
+# host = "127.0.0.1"
+# allowed_origins = ["http://localhost:3000"]
+
+# Uncomment to serve the HTTP API
+# [http]
+# port = 8013
+# host = "127.0.0.1"
+# allowed_origins = ["http://localhost:3000"]
+
+# Sends that fail are saved here and retried with exponential backoff
+# [outbox]
+# path = "outbox.json"
+# max_attempts = 5
+# backoff_ms = 2000
+# max_batch = 10
+
+# Every message is archived here, search it with `holly search`
+# [history]
+# enabled = true
+# dir = "history"
+
+# Uncomment to limit how fast messages and files are sent
+# [rate_limit]
+# on_limit = "queue" # or "reject"
+# global = { burst = 20, per_minute = 30 }
+# per_chat = { burst = 5, per_minute = 10 }
 "#;
 
 /// Holly configuration file
@@ -27,7 +71,37 @@ pub struct Config {
     pub refresh_rate: usize,
     pub latency: usize,
     pub gecko: Gecko,
-    pub tcp: Tcp,
+    pub tcp: Option<Tcp>,
+    pub unix: Option<Unix>,
+    pub websocket: Option<WebSocket>,
+    pub http: Option<Http>,
+    /// Tokens children authenticate with. If there are none, anyone can do anything.
+    #[serde(default, rename = "token", skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<Token>,
+    /// How events are queued for each child
+    #[serde(default)]
+    pub broadcast: Broadcast,
+    /// File the last messages seen in each chat are saved to
+    #[serde(default = "default_cache_path")]
+    pub cache_path: String,
+    /// Where downloaded attachments are saved
+    #[serde(default = "default_attachment_dir")]
+    pub attachment_dir: String,
+    /// Archive of every message read or sent
+    #[serde(default)]
+    pub history: History,
+    /// How many recent messages to keep for children that reconnect
+    #[serde(default = "default_replay_buffer")]
+    pub replay_buffer: usize,
+    /// Where sends wait until they go through
+    #[serde(default)]
+    pub outbox: Outbox,
+    /// How fast sends can go out
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    /// URLs to POST new messages to
+    #[serde(default, rename = "webhook", skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<Webhook>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,6 +115,165 @@ pub struct Gecko {
 pub struct Tcp {
     pub port: u16,
     pub host: String,
+    /// How JSON objects are delimited on the socket
+    #[serde(default)]
+    pub framing: Framing,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// A Unix domain socket for children on the same host
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Unix {
+    pub path: String,
+    /// File permissions for the socket, such as `0o660`
+    pub mode: Option<u32>,
+    /// How JSON objects are delimited on the socket
+    #[serde(default)]
+    pub framing: Framing,
+}
+
+/// A WebSocket listener for browser based children
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebSocket {
+    pub port: u16,
+    pub host: String,
+    /// Web pages allowed to connect, such as `http://localhost:3000`.
+    /// Connections from other pages are refused, those from outside a browser are always let in.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+/// The REST API for scripts
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Http {
+    pub port: u16,
+    pub host: String,
+    /// Web pages allowed to call the API, such as `http://localhost:3000`.
+    /// Requests from other pages are refused, those from outside a browser are always let in.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Broadcast {
+    /// How many events can wait for a slow child
+    pub queue_size: usize,
+    /// What to do when a child's queue is full
+    pub overflow: Overflow,
+}
+
+impl Default for Broadcast {
+    fn default() -> Self {
+        Self {
+            queue_size: 256,
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Outbox {
+    /// File pending sends are saved to
+    pub path: String,
+    /// How many times to try a send before giving up on it
+    pub max_attempts: u32,
+    /// How long to wait after the first failure, doubling after each one
+    pub backoff_ms: u64,
+    /// Most sends to make before going back to reading chats
+    pub max_batch: usize,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self {
+            path: "outbox.json".to_string(),
+            max_attempts: 5,
+            backoff_ms: 2000,
+            max_batch: 10,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct History {
+    pub enabled: bool,
+    /// Directory with a JSON lines file for each chat
+    pub dir: String,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: "history".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    /// What to do with sends over the limit
+    pub on_limit: OnLimit,
+    /// Shared by every chat
+    pub global: Option<Rate>,
+    /// Applied to each chat without its own entry in `chats`
+    pub per_chat: Option<Rate>,
+    /// Limits for specific chats, by chat ID
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub chats: HashMap<String, Rate>,
+}
+
+/// A token bucket
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Rate {
+    /// How many sends can go out back to back
+    pub burst: u32,
+    /// How many sends the bucket refills with each minute
+    pub per_minute: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Only POST messages from these chats. Empty means every chat.
+    #[serde(default)]
+    pub chat_ids: Vec<String>,
+    /// Signs each body with HMAC-SHA256 in the X-Holly-Signature header
+    pub secret: Option<String>,
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout_ms: u64,
+    /// How many times to retry a failed delivery, backing off exponentially
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+}
+
+fn default_cache_path() -> String {
+    "cache.json".to_string()
+}
+
+fn default_attachment_dir() -> String {
+    "attachments".to_string()
+}
+
+fn default_replay_buffer() -> usize {
+    1000
+}
+
+fn default_webhook_timeout() -> u64 {
+    5000
+}
+
+fn default_webhook_retries() -> u32 {
+    3
 }
 
 impl Config {
@@ -141,7 +374,7 @@ impl Config {
                                 .unwrap()
                                 == 0,
                         },
-                        tcp: Tcp {
+                        tcp: Some(Tcp {
                             port: loop {
                                 let port: String = Input::with_theme(&ColorfulTheme::default())
                                     .with_prompt(
@@ -166,7 +399,20 @@ impl Config {
                                 }
                                 println!("Enter an IP address...");
                             },
-                        },
+                            framing: Framing::default(),
+                        }),
+                        unix: None,
+                        websocket: None,
+                        http: None,
+                        tokens: Vec::new(),
+                        broadcast: Broadcast::default(),
+                        cache_path: default_cache_path(),
+                        attachment_dir: default_attachment_dir(),
+                        history: History::default(),
+                        replay_buffer: default_replay_buffer(),
+                        outbox: Outbox::default(),
+                        rate_limit: RateLimit::default(),
+                        webhooks: Vec::new(),
                     };
                     std::fs::write(path, toml::to_string(&new_config).unwrap())
                         .expect("Unable to write new config file");
@@ -0,0 +1,221 @@
+// Jackson Coxson
+// Every message Holly reads or sends, appended to a JSON lines file per chat.
+// Unlike the cache and the replay buffer this is never trimmed, so it can answer
+// what was said in a chat long after it scrolled out of the page.
+
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chat::{ChatMessage, SELF_SENDER},
+    config,
+    protocol::HistoryQuery,
+};
+
+/// How many entries a search returns if it doesn't say
+const DEFAULT_LIMIT: usize = 100;
+
+/// A message as it was archived
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// When Holly read or sent the message
+    pub at: DateTime<Utc>,
+    /// Whether Holly sent this herself
+    #[serde(default)]
+    pub sent: bool,
+    #[serde(flatten)]
+    pub message: ChatMessage,
+}
+
+pub struct History {
+    /// Where the files live, or `None` if archiving is off
+    dir: Option<PathBuf>,
+}
+
+impl History {
+    pub fn new(config: &config::History) -> Self {
+        let dir = config.enabled.then(|| PathBuf::from(&config.dir));
+        if let Some(dir) = &dir {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                error!("Unable to create the history directory {dir:?}: {e:?}");
+            }
+        }
+        Self { dir }
+    }
+
+    /// Archives a message read from a chat
+    pub fn received(&self, message: &ChatMessage) {
+        self.append(Entry {
+            at: Utc::now(),
+            sent: false,
+            message: message.clone(),
+        });
+    }
+
+    /// Archives a message Holly sent
+    pub fn sent(&self, chat_id: &str, content: &str) {
+        self.append(Entry {
+            at: Utc::now(),
+            sent: true,
+            message: ChatMessage {
+                sender: SELF_SENDER.to_string(),
+                content: content.to_string(),
+                chat_id: chat_id.to_string(),
+                message_id: None,
+                reply_to: None,
+                attachments: Vec::new(),
+                reactions: Vec::new(),
+                from_self: true,
+                sent_at: None,
+                detected_at: None,
+            },
+        });
+    }
+
+    fn append(&self, entry: Entry) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let path = dir.join(file_name(&entry.message.chat_id));
+        let mut line = match serde_json::to_vec(&entry) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Unable to serialize a history entry: {e:?}");
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(&line))
+        {
+            error!("Unable to write to the history at {path:?}: {e:?}");
+        }
+    }
+
+    /// The most recent entries matching the query, oldest first
+    pub fn search(&self, query: &HistoryQuery) -> std::io::Result<Vec<Entry>> {
+        let Some(dir) = &self.dir else {
+            return Ok(Vec::new());
+        };
+        let files = match &query.chat_id {
+            Some(chat_id) => vec![dir.join(file_name(chat_id))],
+            None => std::fs::read_dir(dir)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|e| e == "jsonl"))
+                .collect(),
+        };
+
+        let text = query.text.as_ref().map(|t| t.to_lowercase());
+        let mut entries = Vec::new();
+        for path in files {
+            let file = match std::fs::File::open(&path) {
+                Ok(f) => f,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for line in BufReader::new(file).lines() {
+                let entry: Entry = match serde_json::from_str(&line?) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        warn!("Skipping a bad line in {path:?}: {e:?}");
+                        continue;
+                    }
+                };
+                if query
+                    .sender
+                    .as_ref()
+                    .is_some_and(|s| *s != entry.message.sender)
+                    || query.since.is_some_and(|s| entry.at < s)
+                    || query.until.is_some_and(|u| entry.at > u)
+                    || text
+                        .as_ref()
+                        .is_some_and(|t| !entry.message.content.to_lowercase().contains(t))
+                {
+                    continue;
+                }
+                entries.push(entry);
+            }
+        }
+
+        entries.sort_by_key(|e| e.at);
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+        let skip = entries.len().saturating_sub(limit);
+        Ok(entries.split_off(skip))
+    }
+}
+
+/// Chat IDs come from the page, so keep them from escaping the directory
+fn file_name(chat_id: &str) -> String {
+    let name: String = chat_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    format!("{name}.jsonl")
+}
+
+/// Searches the archive from the command line.
+/// `holly search [TEXT] [--chat ID] [--sender NAME] [--since DATE] [--until DATE] [--limit N]`
+pub fn cli(config: &config::History, args: &[String]) -> Result<(), String> {
+    let mut query = HistoryQuery::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{arg} needs a value"))
+        };
+        match arg.as_str() {
+            "--chat" => query.chat_id = Some(value()?),
+            "--sender" => query.sender = Some(value()?),
+            "--since" => query.since = Some(parse_date(&value()?)?),
+            "--until" => query.until = Some(parse_date(&value()?)?),
+            "--limit" => {
+                query.limit = Some(
+                    value()?
+                        .parse()
+                        .map_err(|_| "--limit needs a number".to_string())?,
+                )
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {arg}")),
+            _ => query.text = Some(arg.clone()),
+        }
+    }
+
+    if !config.enabled {
+        return Err("The history archive is disabled in the config".to_string());
+    }
+    let entries = History::new(config)
+        .search(&query)
+        .map_err(|e| format!("Unable to read the history: {e}"))?;
+    for entry in entries {
+        println!(
+            "{} [{}] {}{}: {}",
+            entry.at.format("%Y-%m-%d %H:%M:%S"),
+            entry.message.chat_id,
+            entry.message.sender,
+            if entry.sent { " (sent)" } else { "" },
+            entry.message.content
+        );
+    }
+    Ok(())
+}
+
+/// Takes either an RFC 3339 timestamp or a plain `YYYY-MM-DD` date, which means midnight UTC
+fn parse_date(date: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(d) = DateTime::parse_from_rfc3339(date) {
+        return Ok(d.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("Unable to parse the date {date:?}, use YYYY-MM-DD or RFC 3339"))
+}
@@ -0,0 +1,298 @@
+// Jackson Coxson
+// A small REST API for cron jobs and shell scripts that don't want to hold a socket open.
+// Every endpoint is passed through the same dispatch as requests from children.
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use std::{sync::Arc, time::Duration};
+
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+
+use crate::{
+    config, outbox,
+    protocol::{Command, MessageTarget, Request, Scope},
+    ratelimit,
+    server::{Hub, Unanswered},
+};
+
+/// How long to wait for a send to go out before answering that it's still queued
+const SEND_WAIT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a command to be carried out
+const COMMAND_WAIT: Duration = Duration::from_secs(120);
+
+#[derive(Deserialize)]
+struct MessageBody {
+    content: String,
+    #[serde(default)]
+    reply_to: Option<MessageTarget>,
+}
+
+#[derive(Deserialize)]
+struct FileBody {
+    path: String,
+}
+
+/// Who may call the API, on top of holding a token
+#[derive(Clone)]
+pub struct Guard {
+    /// `Host` headers that name us, or empty to take any
+    hosts: Arc<Vec<String>>,
+    /// Web pages allowed to call the API
+    origins: Arc<Vec<String>>,
+}
+
+impl Guard {
+    pub fn new(config: &config::Http) -> Self {
+        let port = config.port;
+        let hosts = match config.host.as_str() {
+            // Listening everywhere, so any name could be ours
+            "0.0.0.0" | "::" => Vec::new(),
+            "127.0.0.1" | "localhost" | "::1" => ["127.0.0.1", "localhost", "[::1]"]
+                .iter()
+                .map(|h| format!("{h}:{port}"))
+                .collect(),
+            host => vec![format!("{host}:{port}")],
+        };
+        Self {
+            hosts: Arc::new(hosts),
+            origins: Arc::new(config.allowed_origins.clone()),
+        }
+    }
+}
+
+/// Serves the API forever
+pub async fn listen_http(listener: TcpListener, guard: Guard, hub: Hub) {
+    let app = Router::new()
+        .route("/chats/:chat_id/messages", post(send_message))
+        .route("/chats/:chat_id/files", post(send_file))
+        .route("/screenshot", post(screenshot))
+        .route("/html", get(html))
+        .route("/refresh", post(refresh))
+        .route("/restart", post(restart))
+        .layer(middleware::from_fn_with_state(guard, check))
+        .with_state(hub);
+
+    if let Err(e) = axum::serve(listener, app).await {
+        warn!("HTTP server stopped: {e:?}");
+    }
+}
+
+/// Refuses web pages that aren't allowed, and requests naming a host that isn't us.
+/// A DNS rebinding page gets past the browser's own checks, but not past the `Host` it sends.
+async fn check(
+    State(guard): State<Guard>,
+    request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        if !guard
+            .origins
+            .iter()
+            .any(|o| o.as_bytes() == origin.as_bytes())
+        {
+            warn!("Refusing HTTP request from origin {origin:?}");
+            return error(StatusCode::FORBIDDEN, "Origin not allowed");
+        }
+    }
+    if !guard.hosts.is_empty() {
+        let host = headers
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default();
+        if !guard.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+            warn!("Refusing HTTP request for host {host:?}");
+            return error(StatusCode::FORBIDDEN, "Unknown host");
+        }
+    }
+    next.run(request).await
+}
+
+async fn send_message(
+    State(hub): State<Hub>,
+    headers: HeaderMap,
+    Path(chat_id): Path<String>,
+    Json(body): Json<MessageBody>,
+) -> Result<Response, Response> {
+    dispatch(
+        &hub,
+        &headers,
+        Request::SendMessage {
+            chat_id,
+            content: body.content,
+            reply_to: body.reply_to,
+        },
+    )
+    .await
+    .map(ok)
+}
+
+async fn send_file(
+    State(hub): State<Hub>,
+    headers: HeaderMap,
+    Path(chat_id): Path<String>,
+    Json(body): Json<FileBody>,
+) -> Result<Response, Response> {
+    dispatch(
+        &hub,
+        &headers,
+        Request::SendFile {
+            chat_id,
+            path: body.path,
+        },
+    )
+    .await
+    .map(ok)
+}
+
+async fn screenshot(State(hub): State<Hub>, headers: HeaderMap) -> Result<Response, Response> {
+    command(&hub, &headers, Command::Screenshot).await
+}
+
+async fn refresh(State(hub): State<Hub>, headers: HeaderMap) -> Result<Response, Response> {
+    command(&hub, &headers, Command::Refresh).await
+}
+
+async fn restart(State(hub): State<Hub>, headers: HeaderMap) -> Result<Response, Response> {
+    command(&hub, &headers, Command::Restart).await
+}
+
+/// Dumps the page HTML and responds with it
+async fn html(State(hub): State<Hub>, headers: HeaderMap) -> Result<Response, Response> {
+    if let Some(refusal) = not_json(&headers) {
+        return Err(refusal);
+    }
+    let result = dispatch(
+        &hub,
+        &headers,
+        Request::Command {
+            command: Command::Html,
+        },
+    )
+    .await?;
+
+    let Some(path) = result
+        .as_ref()
+        .and_then(|r| r.get("path"))
+        .and_then(Value::as_str)
+    else {
+        return Err(error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Holly saved no HTML",
+        ));
+    };
+    match tokio::fs::read_to_string(path).await {
+        Ok(html) => {
+            Ok(([(header::CONTENT_TYPE, "text/html; charset=utf-8")], html).into_response())
+        }
+        Err(e) => Err(error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Unable to read {path}: {e}"),
+        )),
+    }
+}
+
+async fn command(hub: &Hub, headers: &HeaderMap, command: Command) -> Result<Response, Response> {
+    if let Some(refusal) = not_json(headers) {
+        return Err(refusal);
+    }
+    dispatch(hub, headers, Request::Command { command })
+        .await
+        .map(ok)
+}
+
+/// Commands have no body, but still have to say they're JSON.
+/// Browsers won't send that to another site without asking first, so web pages can't fire them.
+/// Returns the refusal for anything else.
+fn not_json(headers: &HeaderMap) -> Option<Response> {
+    let json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.starts_with("application/json"));
+    (!json).then(|| {
+        error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Expected Content-Type: application/json",
+        )
+    })
+}
+
+/// Authenticates the caller and passes the request to the main loop
+async fn dispatch(
+    hub: &Hub,
+    headers: &HeaderMap,
+    request: Request,
+) -> Result<Option<Value>, Response> {
+    let scopes = if hub.requires_auth() {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+        match token.and_then(|t| hub.scopes_for(t)) {
+            Some(s) => s,
+            None => return Err(error(StatusCode::UNAUTHORIZED, "Invalid token")),
+        }
+    } else {
+        Scope::ALL.to_vec()
+    };
+    if let Some(scope) = request.scope().filter(|s| !scopes.contains(s)) {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            &format!("Missing the {scope} scope"),
+        ));
+    }
+
+    info!("HTTP request: {request:?}");
+    let send = matches!(
+        request,
+        Request::SendMessage { .. } | Request::SendFile { .. }
+    );
+    let wait = if send { SEND_WAIT } else { COMMAND_WAIT };
+    match hub.dispatch(request, scopes, wait).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(message)) => Err(error(failure_status(&message, send), &message)),
+        // The send is still in the outbox and will go out, so retrying would send it twice
+        Err(Unanswered::TimedOut) if send => Err((
+            StatusCode::ACCEPTED,
+            Json(json!({ "ok": true, "pending": true, "result": null })),
+        )
+            .into_response()),
+        Err(Unanswered::TimedOut) => Err(error(
+            StatusCode::GATEWAY_TIMEOUT,
+            &Unanswered::TimedOut.to_string(),
+        )),
+        Err(e) => Err(error(StatusCode::SERVICE_UNAVAILABLE, &e.to_string())),
+    }
+}
+
+/// The status for a request Holly carried out that failed.
+/// Sends Messenger refused are the caller's to fix, the browser failing is ours.
+fn failure_status(message: &str, send: bool) -> StatusCode {
+    if message.starts_with(ratelimit::LIMITED) {
+        StatusCode::TOO_MANY_REQUESTS
+    } else if !send {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else if message.starts_with(outbox::GAVE_UP) {
+        StatusCode::BAD_GATEWAY
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    }
+}
+
+fn ok(result: Option<Value>) -> Response {
+    Json(json!({ "ok": true, "result": result })).into_response()
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "ok": false, "error": message }))).into_response()
+}
@@ -1,25 +1,44 @@
 // Jackson Coxson
 
-use std::sync::Arc;
-
-use chat::ChatMessage;
 use log::{debug, error, info, warn};
-use thirtyfour::error::WebDriverResult;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::Mutex,
+use thirtyfour::{error::WebDriverResult, WebElement};
+use tokio::sync::mpsc::Receiver;
+
+use crate::{
+    cache::{Cache, Changes},
+    chat::ChatMessage,
+    config::Config,
+    outbox::Outbox,
+    protocol::{Command, Event, Inbound, Outcome, Request},
+    queue::EventQueue,
+    ratelimit::{OnLimit, RateLimiter},
 };
 
-use crate::cache::Cache;
-
+mod backfill;
 mod browser;
 mod cache;
 mod chat;
 mod config;
-
-async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
-    let config = config::Config::load();
-    let client = browser::Browser::new(&config).await.unwrap();
+mod history;
+mod http;
+mod outbox;
+mod protocol;
+mod queue;
+mod ratelimit;
+mod server;
+mod timestamp;
+mod webhook;
+
+async fn entry(
+    config: &Config,
+    hub: &server::Hub,
+    rx: &mut Receiver<Inbound>,
+    outbox: &mut Outbox,
+    limiter: &mut RateLimiter,
+    cache: &mut Cache,
+    clear_cookies: bool,
+) -> WebDriverResult<()> {
+    let client = browser::Browser::new(config).await.unwrap();
 
     if !clear_cookies {
         client.load_cookies().await.unwrap();
@@ -40,91 +59,16 @@ async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
         client.dump_cookies().await.unwrap();
     }
     client.dump_cookies().await.unwrap();
-    if let Some(pin) = config.e2ee_pin {
-        client.enter_e2ee_pin(pin).await;
+    if let Some(pin) = &config.e2ee_pin {
+        client.enter_e2ee_pin(pin.clone()).await;
     }
 
-    let listener =
-        tokio::net::TcpListener::bind(format!("{}:{}", config.tcp.host, config.tcp.port))
-            .await
-            .unwrap();
-
-    let senders = Arc::new(Mutex::new(Vec::new()));
-    let tcp_senders = senders.clone();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<ChatMessage>(100);
-
-    tokio::spawn(async move {
-        loop {
-            if let Ok((mut stream, addr)) = listener.accept().await {
-                info!("Accepted connection from {:?}", addr);
-
-                let (local_tx, mut local_rx) = tokio::sync::mpsc::channel::<ChatMessage>(100);
-                let tx = tx.clone();
-                tcp_senders.lock().await.push(local_tx);
-
-                tokio::spawn(async move {
-                    loop {
-                        let mut buf = [0; 4096];
-                        tokio::select! {
-                            msg = local_rx.recv() => {
-                                let msg = serde_json::to_string(&msg).unwrap();
-                                if stream.write(msg.as_bytes()).await.is_err() {
-                                    break;
-                                }
-                                if stream.flush().await.is_err() {
-                                    warn!("Unable to flush message to client");
-                                    break;
-                                }
-                            }
-                            x = stream.read(&mut buf) => {
-                                if let Ok(x) = x {
-                                    if let Ok(buf) = String::from_utf8(buf[0..x].to_vec()) {
-                                        if x == 0 {
-                                            break;
-                                        }
-                                        // Split the buf into JSON packets
-                                        // As we've learned, sometimes nagle's algo will squish them
-                                        // together into one packet, so we need to split them up
-                                        let packets = buf.split("}{")
-                                            .map(|s| {
-                                                let s = if s.ends_with('}') {
-                                                    s.to_string()
-                                                } else {
-                                                    format!("{s}}}")
-                                                };
-                                                if s.starts_with('{') {
-                                                    s.to_string()
-                                                } else {
-                                                    format!("{{{s}")
-                                                }
-                                            })
-                                            .collect::<Vec<_>>();
-
-                                        for packet in packets {
-                                            if let Ok(mut msg) = serde_json::from_str::<ChatMessage>(&packet) {
-                                                msg.clean();
-                                                tx.send(msg).await.unwrap();
-                                            } else {
-                                                warn!("Failed to parse msg: {:?}", buf);
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                });
-            }
-        }
-    });
-
-    let mut cache = Cache::new();
+    // Anything that arrived while Holly was down is new to the cache saved before
     let current_chat = client.get_current_chat().await.unwrap();
-    cache
-        .check(&current_chat, &client.get_messages(false).await.unwrap())
-        .await;
+    let rows = client.get_messages_with_rows().await.unwrap();
+    let messages: Vec<ChatMessage> = rows.iter().map(|(_, m)| m.clone()).collect();
+    let changes = cache.check(&current_chat, &messages).await;
+    publish(&client, hub, &current_chat, changes, &rows).await;
 
     let mut error_count: u8 = 0;
 
@@ -141,7 +85,7 @@ async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
         }
 
         // See if the current chat has different messages than before
-        let current_message = match client.get_messages(false).await {
+        let rows = match client.get_messages_with_rows().await {
             Ok(c) => c,
             Err(e) => {
                 error!("Unable to get messages: {:?}", e);
@@ -170,6 +114,7 @@ async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
             }
         };
 
+        let current_message: Vec<ChatMessage> = rows.iter().map(|(_, m)| m.clone()).collect();
         if current_message != second_sample {
             warn!("Message samples don't match!");
             continue;
@@ -187,90 +132,168 @@ async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
             }
         };
 
-        if let Some(unread_messages) = cache.check(&current_chat, &current_message).await {
-            for message in unread_messages {
-                info!(
-                    "{} in {}: {}",
-                    message.sender, current_chat, message.content
+        let changes = cache.check(&current_chat, &current_message).await;
+        publish(&client, hub, &current_chat, changes, &rows).await;
+
+        // Handle everything children have asked for. Sends go to the outbox.
+        while let Ok(Inbound {
+            request_id,
+            request,
+            reply,
+            scopes,
+        }) = rx.try_recv()
+        {
+            if let Some(scope) = request.scope().filter(|s| !scopes.contains(s)) {
+                report(
+                    &reply,
+                    request_id,
+                    Err(format!("Missing the {scope} scope")),
                 );
-                let blocking_senders = senders.clone();
-                tokio::task::spawn_blocking(move || {
-                    blocking_senders
-                        .blocking_lock()
-                        .retain(|sender| sender.blocking_send(message.clone()).is_ok());
-                });
+                continue;
             }
-        }
 
-        // Possibly send a message
-        if let Ok(msg) = rx.try_recv() {
-            match msg.sender.as_str() {
-                "<screenshot>" => {
-                    if let Err(e) = client.screenshot_log().await {
-                        error!("Unable to take screenshot!");
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                    }
-                    continue;
+            let outcome = match request {
+                Request::Command {
+                    command: Command::Screenshot,
+                } => client
+                    .screenshot_log()
+                    .await
+                    .inspect_err(|_| error!("Unable to take screenshot!"))
+                    .map(|path| Ok(Some(serde_json::json!({ "path": path })))),
+                Request::Command {
+                    command: Command::Html,
+                } => client
+                    .html_log()
+                    .await
+                    .inspect_err(|_| error!("Unable to take html log!"))
+                    .map(|path| Ok(Some(serde_json::json!({ "path": path })))),
+                Request::Command {
+                    command: Command::Restart,
+                } => {
+                    report(&reply, request_id, Ok(None));
+                    return Ok(());
                 }
-                "<html>" => {
-                    if let Err(e) = client.html_log().await {
-                        error!("Unable to take html log!");
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
+                Request::Command {
+                    command: Command::Refresh,
+                } => client
+                    .refresh()
+                    .await
+                    .inspect_err(|e| error!("Unable to refresh: {:?}", e))
+                    .map(|()| Ok(None)),
+                Request::Backfill {
+                    chat_id,
+                    limit,
+                    since,
+                } => backfill::run(&client, &chat_id, limit, since, &reply, request_id.clone())
+                    .await
+                    .inspect_err(|e| error!("Unable to backfill {chat_id}: {:?}", e))
+                    .map(|summary| Ok(Some(summary))),
+                Request::DownloadAttachment { url } => match client.cookie_header().await {
+                    // Downloads can be slow, so they don't hold up the browser
+                    Ok(cookies) => {
+                        let dir = config.attachment_dir.clone();
+                        tokio::spawn(async move {
+                            let outcome = browser::download_attachment(&url, &dir, &cookies)
+                                .await
+                                .map(|path| Some(serde_json::json!({ "path": path })));
+                            report(&reply, request_id, outcome);
+                        });
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Unable to get cookies for a download: {:?}", e);
+                        Err(e)
                     }
+                },
+                Request::Subscribe(_)
+                | Request::Auth { .. }
+                | Request::Resume { .. }
+                | Request::History(_) => {
+                    // The server answers these itself, landing here is a bug but not worth a crash
+                    let e = "Subscriptions, auth, replays and history are handled by the server";
+                    report(&reply, request_id, Err(e.to_string()));
                     continue;
                 }
-                "<restart>" => return Ok(()),
-                "<refresh>" => {
-                    client.refresh().await?;
+                Request::SendMessage { .. }
+                | Request::SendFile { .. }
+                | Request::React { .. }
+                | Request::EditMessage { .. }
+                | Request::UnsendMessage { .. } => {
+                    outbox.push(request, request_id, reply);
                     continue;
                 }
-                "<file>" => {
-                    info!("Sending file!");
-                    if let Err(e) = client.go_to_chat(&msg.chat_id).await {
-                        error!("Unable to go to chat for file send: {:?}", e);
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                        continue;
+            };
+
+            match outcome {
+                Ok(result) => report(&reply, request_id, result),
+                Err(e) => {
+                    report(&reply, request_id, Err(e.to_string()));
+                    error_count += 1;
+                    if error_count > 10 {
+                        return Err(e);
                     }
-                    if let Err(e) = client.send_file(&msg.content).await {
-                        error!("Unable to send file: {:?}", e);
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                        continue;
+                }
+            }
+        }
+
+        // Send everything that's due, visiting each chat once
+        let mut current_chat: Option<String> = None;
+        let mut blocked_chat: Option<String> = None;
+        for pending in outbox.batch(config.outbox.max_batch) {
+            let chat_id = pending.chat_id();
+            if blocked_chat.as_deref() == Some(chat_id) {
+                // Keep the rest of this chat's sends in order behind the one held back
+                continue;
+            }
+            if let Err(wait) = limiter.take(chat_id) {
+                match limiter.on_limit() {
+                    OnLimit::Queue => {
+                        outbox.defer(pending.id, wait);
+                        blocked_chat = Some(chat_id.to_string());
                     }
-                    continue;
+                    OnLimit::Reject => outbox.rejected(
+                        pending.id,
+                        format!(
+                            "{}, try again in {}s",
+                            ratelimit::LIMITED,
+                            wait.as_secs() + 1
+                        ),
+                    ),
                 }
-                _ => {
-                    info!("Sending message: {:?}", msg);
-                    if let Err(e) = client.go_to_chat(&msg.chat_id).await {
-                        error!("Unable to go to chat for send: {:?}", e);
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                        continue;
+                continue;
+            }
+            let result = if current_chat.as_deref() == Some(chat_id) {
+                send(&client, config, &pending.request).await
+            } else {
+                match client.go_to_chat(chat_id).await {
+                    Ok(()) => {
+                        current_chat = Some(chat_id.to_string());
+                        send(&client, config, &pending.request).await
                     }
-                    tokio::time::sleep(std::time::Duration::from_millis(config.latency as u64))
-                        .await;
-                    if let Err(e) = client.send_message(&msg.content).await {
-                        error!("Unable to send message: {:?}", e);
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                        continue;
+                    Err(e) => {
+                        error!("Unable to go to chat {chat_id} to send: {:?}", e);
+                        Err(e)
+                    }
+                }
+            };
+            match result {
+                Ok(Ok(())) => {
+                    if let Request::SendMessage {
+                        chat_id, content, ..
+                    } = &pending.request
+                    {
+                        hub.history.sent(chat_id, content);
+                    }
+                    outbox.succeeded(pending.id, None)
+                }
+                Ok(Err(e)) => outbox.rejected(pending.id, e),
+                Err(e) => {
+                    blocked_chat = Some(chat_id.to_string());
+                    outbox.failed(pending.id, e.to_string());
+                    error_count += 1;
+                    if error_count > 10 {
+                        return Err(e);
                     }
-                    continue;
                 }
             }
         }
@@ -310,8 +333,167 @@ async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
     }
 }
 
+/// Hands what changed in a chat to the children. `rows` is the sample the changes were found in.
+async fn publish(
+    client: &browser::Browser,
+    hub: &server::Hub,
+    chat_id: &str,
+    mut changes: Changes,
+    rows: &[(WebElement, ChatMessage)],
+) {
+    // Holly's own messages are archived as she sends them, and children don't need to hear themselves
+    changes.messages.retain(|m| !m.from_self);
+    if let Err(e) = client.read_sent_times(&mut changes.messages, rows).await {
+        warn!("Unable to read when messages were sent: {:?}", e);
+    }
+    for message in changes.messages {
+        info!("{} in {}: {}", message.sender, chat_id, message.content);
+        hub.publish(message);
+    }
+    for message in changes.reactions {
+        info!(
+            "Reactions on {:?} in {} are now {:?}",
+            message.content, chat_id, message.reactions
+        );
+        hub.publish_reactions(message);
+    }
+}
+
+/// Binds every configured listener. They keep running while the browser restarts.
+async fn listen(config: &Config, hub: &server::Hub) {
+    if let Some(tcp) = &config.tcp {
+        let listener = tokio::net::TcpListener::bind(format!("{}:{}", tcp.host, tcp.port))
+            .await
+            .unwrap();
+        tokio::spawn(server::listen_tcp(listener, tcp.framing, hub.clone()));
+    }
+    if let Some(unix) = &config.unix {
+        let listener = server::bind_unix(&unix.path, unix.mode).unwrap();
+        tokio::spawn(server::listen_unix(listener, unix.framing, hub.clone()));
+    }
+    if let Some(websocket) = &config.websocket {
+        let listener =
+            tokio::net::TcpListener::bind(format!("{}:{}", websocket.host, websocket.port))
+                .await
+                .unwrap();
+        tokio::spawn(server::listen_websocket(
+            listener,
+            websocket.allowed_origins.clone(),
+            hub.clone(),
+        ));
+    }
+    if let Some(http) = &config.http {
+        let listener = tokio::net::TcpListener::bind(format!("{}:{}", http.host, http.port))
+            .await
+            .unwrap();
+        let guard = http::Guard::new(http);
+        tokio::spawn(http::listen_http(listener, guard, hub.clone()));
+    }
+    if config.tcp.is_none()
+        && config.unix.is_none()
+        && config.websocket.is_none()
+        && config.http.is_none()
+    {
+        warn!("No sockets are configured, children won't be able to connect!");
+    }
+}
+
+/// Sends a message, file, reaction, edit or unsend from the outbox to the chat that's already open.
+/// The inner error is a refusal that retrying won't fix.
+async fn send(
+    client: &browser::Browser,
+    config: &Config,
+    request: &Request,
+) -> WebDriverResult<Result<(), String>> {
+    match request {
+        Request::SendFile { chat_id, path } => {
+            info!("Sending file to {chat_id}: {path}");
+            client
+                .send_file(path)
+                .await
+                .inspect_err(|e| error!("Unable to send file: {:?}", e))
+                .map(|r| r.map_err(|e| e.to_string()))
+        }
+        Request::SendMessage {
+            chat_id,
+            content,
+            reply_to,
+        } => {
+            info!("Sending message to {chat_id}: {content:?}");
+            if let Some(target) = reply_to {
+                if let Err(e) = client.start_reply(target).await? {
+                    return Ok(Err(e));
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(config.latency as u64)).await;
+            client
+                .send_message(content)
+                .await
+                .inspect_err(|e| error!("Unable to send message: {:?}", e))
+                .map(Ok)
+        }
+        Request::React {
+            chat_id,
+            target,
+            emoji,
+        } => {
+            info!("Reacting with {emoji} in {chat_id} to {target:?}");
+            client
+                .react(target, emoji)
+                .await
+                .inspect_err(|e| error!("Unable to react: {:?}", e))
+        }
+        Request::EditMessage {
+            chat_id,
+            target,
+            content,
+        } => {
+            info!("Editing {target:?} in {chat_id} to {content:?}");
+            tokio::time::sleep(std::time::Duration::from_millis(config.latency as u64)).await;
+            client
+                .edit_message(target, content)
+                .await
+                .inspect_err(|e| error!("Unable to edit message: {:?}", e))
+        }
+        Request::UnsendMessage { chat_id, target } => {
+            info!("Unsending {target:?} in {chat_id}");
+            client
+                .unsend_message(target)
+                .await
+                .inspect_err(|e| error!("Unable to unsend message: {:?}", e))
+        }
+        _ => Ok(Err(
+            "Only messages, files, reactions and edits can be sent".to_string()
+        )),
+    }
+}
+
+/// Tells the child how its request went, if it gave an id to report under
+fn report(reply: &EventQueue, request_id: Option<String>, result: Outcome) {
+    if let Err(e) = &result {
+        warn!("Request {request_id:?} failed: {e}");
+    }
+    let Some(request_id) = request_id else {
+        return;
+    };
+    if !reply.push(Event::outcome(request_id, result)) {
+        warn!("Unable to report the outcome of a request, the client is gone");
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("search") {
+        env_logger::init();
+        let config = Config::load();
+        if let Err(e) = history::cli(&config.history, &args[2..]) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     println!("Starting Holly core...");
 
     if std::env::var("RUST_LOG").is_err() {
@@ -321,11 +503,30 @@ async fn main() {
     env_logger::init();
     info!("Logger initialized");
 
+    let config = Config::load();
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Inbound>(100);
+    let hub = server::Hub::new(tx, &config);
+    listen(&config, &hub).await;
+    let mut outbox = Outbox::load(&config.outbox);
+    let mut limiter = RateLimiter::new(&config.rate_limit);
+    let mut cache = Cache::load(&config.cache_path);
+
     let mut last_error = std::time::Instant::now();
     let mut clear_cookies = false;
 
     loop {
-        if let Err(e) = entry(clear_cookies).await {
+        if let Err(e) = entry(
+            &config,
+            &hub,
+            &mut rx,
+            &mut outbox,
+            &mut limiter,
+            &mut cache,
+            clear_cookies,
+        )
+        .await
+        {
             error!("Holly crashed with {:?}", e);
             if last_error.elapsed().as_secs() > 60 {
                 tokio::time::sleep(std::time::Duration::from_secs(30)).await;
@@ -0,0 +1,309 @@
+// Jackson Coxson
+// Messages waiting to be sent, saved to disk so they survive the browser and Holly restarting.
+// Failed sends are retried with exponential backoff, and the child that asked
+// for the send is told once it either goes through or we give up on it.
+
+use std::{io::Write, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config,
+    protocol::{Event, Outcome, Request},
+    queue::EventQueue,
+};
+
+/// What a send that failed too many times is reported with
+pub const GAVE_UP: &str = "Gave up";
+
+/// A send that hasn't gone through yet
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Pending {
+    pub id: u64,
+    pub request: Request,
+    pub attempts: u32,
+    pub next_attempt: DateTime<Utc>,
+    /// Who to tell how it went. Connections don't survive a restart, so this isn't saved.
+    #[serde(skip)]
+    origin: Option<Origin>,
+}
+
+#[derive(Clone)]
+struct Origin {
+    request_id: String,
+    reply: Arc<EventQueue>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Saved {
+    next_id: u64,
+    pending: Vec<Pending>,
+}
+
+pub struct Outbox {
+    path: String,
+    max_attempts: u32,
+    backoff: Duration,
+    saved: Saved,
+}
+
+impl Pending {
+    pub fn chat_id(&self) -> &str {
+        match &self.request {
+            Request::SendMessage { chat_id, .. }
+            | Request::SendFile { chat_id, .. }
+            | Request::React { chat_id, .. }
+            | Request::EditMessage { chat_id, .. }
+            | Request::UnsendMessage { chat_id, .. } => chat_id,
+            _ => "",
+        }
+    }
+
+    fn report(&self, outcome: Outcome) {
+        if let Some(origin) = &self.origin {
+            if !origin
+                .reply
+                .push(Event::outcome(origin.request_id.clone(), outcome))
+            {
+                warn!("Unable to report the outcome of a send, the client is gone");
+            }
+        }
+    }
+}
+
+impl Outbox {
+    /// Loads the outbox from disk, or starts an empty one
+    pub fn load(config: &config::Outbox) -> Self {
+        let saved = match std::fs::read_to_string(&config.path) {
+            Ok(contents) => match serde_json::from_str::<Saved>(&contents) {
+                Ok(s) => {
+                    if !s.pending.is_empty() {
+                        info!("Loaded {} pending sends from the outbox", s.pending.len());
+                    }
+                    s
+                }
+                Err(e) => {
+                    error!("Outbox at {} is corrupt, starting over: {e:?}", config.path);
+                    Saved::default()
+                }
+            },
+            Err(_) => Saved::default(),
+        };
+        Self {
+            path: config.path.clone(),
+            max_attempts: config.max_attempts.max(1),
+            backoff: Duration::from_millis(config.backoff_ms),
+            saved,
+        }
+    }
+
+    /// Queues a send to be attempted as soon as possible
+    pub fn push(&mut self, request: Request, request_id: Option<String>, reply: Arc<EventQueue>) {
+        let id = self.saved.next_id;
+        self.saved.next_id += 1;
+        self.saved.pending.push(Pending {
+            id,
+            request,
+            attempts: 0,
+            next_attempt: Utc::now(),
+            origin: request_id.map(|request_id| Origin { request_id, reply }),
+        });
+        self.save();
+    }
+
+    /// Up to `max` sends that are ready to be attempted, grouped by chat so each chat is only visited once.
+    /// Sends wait for older ones to the same chat, so they arrive in order.
+    pub fn batch(&self, max: usize) -> Vec<Pending> {
+        let now = Utc::now();
+        let mut blocked: Vec<&str> = Vec::new();
+        let mut chats: Vec<&str> = Vec::new();
+        let mut due: Vec<&Pending> = Vec::new();
+        for pending in &self.saved.pending {
+            if blocked.contains(&pending.chat_id()) {
+                continue;
+            }
+            if pending.next_attempt > now {
+                blocked.push(pending.chat_id());
+                continue;
+            }
+            if !chats.contains(&pending.chat_id()) {
+                chats.push(pending.chat_id());
+            }
+            due.push(pending);
+        }
+        // Stable, so each chat's sends stay oldest first
+        due.sort_by_key(|p| chats.iter().position(|c| *c == p.chat_id()));
+        due.into_iter().take(max).cloned().collect()
+    }
+
+    /// The send went through
+    pub fn succeeded(&mut self, id: u64, result: Option<serde_json::Value>) {
+        if let Some(pending) = self.remove(id) {
+            pending.report(Ok(result));
+        }
+    }
+
+    /// The send can never succeed, so don't bother retrying it
+    pub fn rejected(&mut self, id: u64, message: String) {
+        if let Some(pending) = self.remove(id) {
+            warn!("Send {id} was rejected: {message}");
+            pending.report(Err(message));
+        }
+    }
+
+    /// The send failed, so back off and try again later, or give up
+    pub fn failed(&mut self, id: u64, message: String) {
+        let Some(pending) = self.saved.pending.iter_mut().find(|p| p.id == id) else {
+            return;
+        };
+        pending.attempts += 1;
+        if pending.attempts >= self.max_attempts {
+            error!(
+                "Giving up on send {id} after {} attempts: {message}",
+                pending.attempts
+            );
+            let attempts = pending.attempts;
+            if let Some(pending) = self.remove(id) {
+                pending.report(Err(format!(
+                    "{GAVE_UP} after {attempts} attempts: {message}"
+                )));
+            }
+            return;
+        }
+
+        let delay = self.backoff * 2u32.saturating_pow(pending.attempts - 1);
+        warn!(
+            "Send {id} failed (attempt {}), retrying in {delay:?}: {message}",
+            pending.attempts
+        );
+        pending.next_attempt = Utc::now() + delay;
+        self.save();
+    }
+
+    /// The send is over the rate limit, so hold it back without counting it as an attempt
+    pub fn defer(&mut self, id: u64, wait: Duration) {
+        let Some(pending) = self.saved.pending.iter_mut().find(|p| p.id == id) else {
+            return;
+        };
+        info!("Send {id} is rate limited, holding it for {wait:?}");
+        pending.next_attempt = Utc::now() + wait;
+        self.save();
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Pending> {
+        let index = self.saved.pending.iter().position(|p| p.id == id)?;
+        let pending = self.saved.pending.remove(index);
+        self.save();
+        Some(pending)
+    }
+
+    fn save(&self) {
+        match serde_json::to_vec_pretty(&self.saved) {
+            Ok(b) => {
+                // Written beside it and moved over it, so a crash mid-write can't leave it torn
+                let tmp = format!("{}.tmp", self.path);
+                let written = std::fs::File::create(&tmp)
+                    .and_then(|mut f| {
+                        f.write_all(&b)?;
+                        f.sync_all()
+                    })
+                    .and_then(|()| std::fs::rename(&tmp, &self.path));
+                if let Err(e) = written {
+                    error!("Unable to save the outbox to {}: {e:?}", self.path);
+                }
+            }
+            Err(e) => error!("Unable to serialize the outbox: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Overflow;
+
+    fn outbox(name: &str, max_attempts: u32) -> Outbox {
+        let path = std::env::temp_dir().join(format!("holly-outbox-{name}.json"));
+        let _ = std::fs::remove_file(&path);
+        Outbox::load(&config::Outbox {
+            path: path.to_string_lossy().to_string(),
+            max_attempts,
+            ..Default::default()
+        })
+    }
+
+    fn push(outbox: &mut Outbox, chat_id: &str, content: &str) -> u64 {
+        let id = outbox.saved.next_id;
+        outbox.push(
+            Request::SendMessage {
+                chat_id: chat_id.to_string(),
+                content: content.to_string(),
+                reply_to: None,
+            },
+            None,
+            Arc::new(EventQueue::new(1, Overflow::DropOldest)),
+        );
+        id
+    }
+
+    fn contents(batch: Vec<Pending>) -> Vec<String> {
+        batch
+            .into_iter()
+            .map(|p| match p.request {
+                Request::SendMessage { content, .. } => content,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_groups_by_chat_in_order() {
+        let mut outbox = outbox("groups", 5);
+        for (chat, content) in [
+            ("a", "a1"),
+            ("b", "b1"),
+            ("a", "a2"),
+            ("c", "c1"),
+            ("b", "b2"),
+        ] {
+            push(&mut outbox, chat, content);
+        }
+        assert_eq!(contents(outbox.batch(10)), ["a1", "a2", "b1", "b2", "c1"]);
+        assert_eq!(contents(outbox.batch(3)), ["a1", "a2", "b1"]);
+        let _ = std::fs::remove_file(&outbox.path);
+    }
+
+    #[test]
+    fn waiting_send_blocks_its_chat() {
+        let mut outbox = outbox("blocks", 5);
+        let a1 = push(&mut outbox, "a", "a1");
+        push(&mut outbox, "a", "a2");
+        let b1 = push(&mut outbox, "b", "b1");
+        push(&mut outbox, "b", "b2");
+
+        outbox.failed(a1, "oops".to_string());
+        assert_eq!(contents(outbox.batch(10)), ["b1", "b2"]);
+        outbox.defer(b1, Duration::from_secs(60));
+        assert!(outbox.batch(10).is_empty());
+        let _ = std::fs::remove_file(&outbox.path);
+    }
+
+    #[test]
+    fn finished_sends_leave_the_outbox() {
+        let mut outbox = outbox("finished", 2);
+        let a1 = push(&mut outbox, "a", "a1");
+        let b1 = push(&mut outbox, "b", "b1");
+        let c1 = push(&mut outbox, "c", "c1");
+
+        outbox.succeeded(a1, None);
+        outbox.rejected(b1, "no".to_string());
+        outbox.failed(c1, "oops".to_string());
+        assert_eq!(outbox.saved.pending.len(), 1);
+        // Out of attempts
+        outbox.failed(c1, "oops".to_string());
+        assert!(outbox.saved.pending.is_empty());
+        let _ = std::fs::remove_file(&outbox.path);
+    }
+}
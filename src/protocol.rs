@@ -0,0 +1,548 @@
+// Jackson Coxson
+// Wire protocol spoken between Holly and her children.
+// Every JSON object is sent as a single frame, either terminated by a newline
+// or prefixed by its length, so neither side has to guess where one ends.
+// Requests and events are tagged with a `type` field. Children written before
+// that existed put commands in the `sender` field of a chat message, which we still accept.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::{chat::ChatMessage, queue::EventQueue};
+
+/// Frames larger than this are rejected to keep a bad client from eating our memory
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// How JSON objects are delimited on the socket
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// One JSON object per line, terminated by `\n`
+    #[default]
+    Ndjson,
+    /// A 4 byte big endian length, followed by that many bytes of JSON
+    LengthPrefixed,
+}
+
+/// A request along with the id the child wants its outcome reported under
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+/// A request sent from a child to Holly
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    /// Types a message into a chat, optionally as a reply to one of its messages
+    SendMessage {
+        chat_id: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<MessageTarget>,
+    },
+    /// Uploads a file from the local disk into a chat
+    SendFile { chat_id: String, path: String },
+    /// Controls Holly herself
+    Command { command: Command },
+    /// Replaces which messages this child is sent
+    Subscribe(Subscription),
+    /// Presents a token from the config to gain its scopes
+    Auth { token: String },
+    /// Replays every buffered message after the given sequence number
+    Resume {
+        resume_from: u64,
+        /// The epoch the sequence number was counted in. A different one means Holly restarted.
+        #[serde(default)]
+        epoch: Option<u64>,
+    },
+    /// Searches the archive of every message Holly has read or sent
+    History(HistoryQuery),
+    /// Scrolls up a chat to read its older messages, streamed back as `backfill` events
+    Backfill {
+        chat_id: String,
+        /// Most messages to read, 500 if unset
+        #[serde(default)]
+        limit: Option<usize>,
+        /// Stop once scrolled back past this time
+        #[serde(default)]
+        since: Option<DateTime<Utc>>,
+    },
+    /// Saves an attachment from a message, answering with its local path
+    DownloadAttachment { url: String },
+    /// Reacts to a message in a chat with an emoji from Messenger's reaction bar
+    React {
+        chat_id: String,
+        target: MessageTarget,
+        emoji: String,
+    },
+    /// Replaces the text of one of Holly's own messages
+    EditMessage {
+        chat_id: String,
+        target: MessageTarget,
+        content: String,
+    },
+    /// Removes one of Holly's own messages for everyone
+    UnsendMessage {
+        chat_id: String,
+        target: MessageTarget,
+    },
+}
+
+/// What a token allows a child to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Receive messages read from chats
+    Read,
+    /// Send messages and files into chats
+    Send,
+    /// Control the browser with commands
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: &'static [Scope] = &[Scope::Read, Scope::Send, Scope::Admin];
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Send => write!(f, "send"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Which messages a child wants to hear about.
+/// Every list that isn't empty must have a match for a message to be sent,
+/// so an empty subscription matches everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subscription {
+    /// Chats to listen to
+    #[serde(default)]
+    pub chat_ids: Vec<String>,
+    /// Exact sender names to listen to
+    #[serde(default)]
+    pub senders: Vec<String>,
+    /// Regular expressions matched against the message content
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// Also send `reactions` events for matching messages.
+    /// Off unless asked for, since children from before they existed would take them for messages.
+    #[serde(default)]
+    pub reactions: bool,
+}
+
+/// Picks out a message on screen in a chat. Every field that's set must match,
+/// and the newest matching message wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageTarget {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// Exact sender name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    /// Exact content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+impl MessageTarget {
+    pub fn matches(&self, message: &ChatMessage) -> bool {
+        (self.message_id.is_some() || self.sender.is_some() || self.content.is_some())
+            && self
+                .message_id
+                .as_ref()
+                .is_none_or(|id| message.message_id.as_ref() == Some(id))
+            && self.sender.as_ref().is_none_or(|s| *s == message.sender)
+            && self.content.as_ref().is_none_or(|c| *c == message.content)
+    }
+}
+
+/// What to look for in the history archive. Every field that's set must match.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryQuery {
+    /// Only search this chat
+    pub chat_id: Option<String>,
+    /// Text the content contains, ignoring case
+    pub text: Option<String>,
+    /// Exact sender name
+    pub sender: Option<String>,
+    /// Only messages from this time on
+    pub since: Option<DateTime<Utc>>,
+    /// Only messages up to this time
+    pub until: Option<DateTime<Utc>>,
+    /// How many of the most recent matches to return, 100 if unset
+    pub limit: Option<usize>,
+}
+
+/// Control commands for the browser
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    /// Takes a screenshot and saves it to `logs/`
+    Screenshot,
+    /// Dumps the page HTML to `logs/`
+    Html,
+    /// Restarts the bot
+    Restart,
+    /// Refreshes the page
+    Refresh,
+}
+
+/// An event sent from Holly to her children
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A new message was read from a chat.
+    /// `seq` increases by one for every message, so children can resume from where they left off.
+    /// It starts over whenever Holly does, which changes the `epoch` it's counted in.
+    Message {
+        epoch: u64,
+        seq: u64,
+        #[serde(flatten)]
+        message: ChatMessage,
+    },
+    /// The request with this id was carried out
+    Ack {
+        request_id: String,
+        /// Whatever the request produced, such as the path of a screenshot
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+    },
+    /// The request with this id failed
+    Error { request_id: String, message: String },
+    /// A page of older messages read by a backfill, oldest first.
+    /// Each page is older than the one before it.
+    Backfill {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        chat_id: String,
+        messages: Vec<ChatMessage>,
+    },
+    /// The reactions on a message already delivered changed. Carries the message with its new reactions.
+    Reactions {
+        #[serde(flatten)]
+        message: ChatMessage,
+    },
+}
+
+/// A request received from a child, along with the channel to answer it on
+pub struct Inbound {
+    pub request_id: Option<String>,
+    pub request: Request,
+    pub reply: Arc<EventQueue>,
+    /// What the child that sent this is allowed to do
+    pub scopes: Vec<Scope>,
+}
+
+impl Envelope {
+    /// Parses a request frame. Frames without a `type` field are parsed
+    /// with the old encoding, where the command lives in the `sender` field.
+    pub fn parse(frame: &[u8]) -> serde_json::Result<Self> {
+        let value: Value = serde_json::from_slice(frame)?;
+        if value.get("type").is_some() {
+            serde_json::from_value(value)
+        } else {
+            Ok(Self {
+                request_id: None,
+                request: serde_json::from_value::<ChatMessage>(value)?.into(),
+            })
+        }
+    }
+
+    /// Digs the request id out of a frame that couldn't be parsed, so the error can be reported
+    pub fn request_id_of(frame: &[u8]) -> Option<String> {
+        serde_json::from_slice::<Value>(frame)
+            .ok()?
+            .get("request_id")?
+            .as_str()
+            .map(str::to_string)
+    }
+}
+
+impl Event {
+    /// The event reporting how a request went
+    pub fn outcome(request_id: String, outcome: Outcome) -> Self {
+        match outcome {
+            Ok(result) => Event::Ack { request_id, result },
+            Err(message) => Event::Error {
+                request_id,
+                message,
+            },
+        }
+    }
+}
+
+/// What a request produced, or why it failed
+pub type Outcome = Result<Option<Value>, String>;
+
+impl Request {
+    /// The scope a child needs to make this request, if any
+    pub fn scope(&self) -> Option<Scope> {
+        match self {
+            Request::SendMessage { .. }
+            | Request::SendFile { .. }
+            | Request::React { .. }
+            | Request::EditMessage { .. }
+            | Request::UnsendMessage { .. } => Some(Scope::Send),
+            // Backfills keep the browser busy for minutes, sending nothing meanwhile
+            Request::Command { .. } | Request::Backfill { .. } => Some(Scope::Admin),
+            Request::Subscribe(_)
+            | Request::Resume { .. }
+            | Request::History(_)
+            | Request::DownloadAttachment { .. } => Some(Scope::Read),
+            Request::Auth { .. } => None,
+        }
+    }
+
+    /// Removes special characters that can't be sent into Messenger
+    pub fn clean(&mut self) {
+        if let Request::SendMessage { content, .. } | Request::EditMessage { content, .. } = self {
+            *content = unidecode::unidecode(content);
+        }
+    }
+}
+
+impl From<ChatMessage> for Request {
+    fn from(msg: ChatMessage) -> Self {
+        match msg.sender.as_str() {
+            "<screenshot>" => Request::Command {
+                command: Command::Screenshot,
+            },
+            "<html>" => Request::Command {
+                command: Command::Html,
+            },
+            "<restart>" => Request::Command {
+                command: Command::Restart,
+            },
+            "<refresh>" => Request::Command {
+                command: Command::Refresh,
+            },
+            "<file>" => Request::SendFile {
+                chat_id: msg.chat_id,
+                path: msg.content,
+            },
+            _ => Request::SendMessage {
+                chat_id: msg.chat_id,
+                content: msg.content,
+                reply_to: None,
+            },
+        }
+    }
+}
+
+/// Reads whole frames off of a stream, no matter how they were split into packets
+pub struct FrameReader<R> {
+    inner: BufReader<R>,
+    framing: Framing,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R, framing: Framing) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            framing,
+        }
+    }
+
+    /// Reads the next frame. Returns `None` once the stream has closed.
+    pub async fn read_frame(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        match self.framing {
+            Framing::Ndjson => loop {
+                let mut buf = Vec::new();
+                let read = (&mut self.inner)
+                    .take(MAX_FRAME_SIZE as u64 + 1)
+                    .read_until(b'\n', &mut buf)
+                    .await?;
+                if read == 0 {
+                    return Ok(None);
+                }
+                if buf.len() > MAX_FRAME_SIZE {
+                    return Err(frame_too_large(buf.len()));
+                }
+                // Tolerate \r\n and blank keep-alive lines
+                while matches!(buf.last(), Some(b'\n' | b'\r')) {
+                    buf.pop();
+                }
+                if !buf.is_empty() {
+                    return Ok(Some(buf));
+                }
+            },
+            Framing::LengthPrefixed => {
+                let len = match self.inner.read_u32().await {
+                    Ok(len) => len as usize,
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                if len > MAX_FRAME_SIZE {
+                    return Err(frame_too_large(len));
+                }
+                let mut buf = vec![0; len];
+                self.inner.read_exact(&mut buf).await?;
+                Ok(Some(buf))
+            }
+        }
+    }
+}
+
+/// Writes a single frame to the stream and flushes it
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    framing: Framing,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    match framing {
+        Framing::Ndjson => {
+            writer.write_all(payload).await?;
+            writer.write_all(b"\n").await?;
+        }
+        Framing::LengthPrefixed => {
+            if payload.len() > MAX_FRAME_SIZE {
+                return Err(frame_too_large(payload.len()));
+            }
+            writer.write_u32(payload.len() as u32).await?;
+            writer.write_all(payload).await?;
+        }
+    }
+    writer.flush().await
+}
+
+fn frame_too_large(len: usize) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Frame of {len} bytes exceeds the {MAX_FRAME_SIZE} byte limit"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn ndjson_split_across_writes() {
+        let (mut tx, rx) = duplex(64);
+        let mut reader = FrameReader::new(rx, Framing::Ndjson);
+        tx.write_all(b"{\"a\":").await.unwrap();
+        tx.write_all(b"1}\n{\"b\"").await.unwrap();
+        tx.write_all(b":2}\n").await.unwrap();
+        drop(tx);
+        assert_eq!(reader.read_frame().await.unwrap().unwrap(), b"{\"a\":1}");
+        assert_eq!(reader.read_frame().await.unwrap().unwrap(), b"{\"b\":2}");
+        assert!(reader.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn ndjson_crlf_and_blank_lines() {
+        let (mut tx, rx) = duplex(64);
+        let mut reader = FrameReader::new(rx, Framing::Ndjson);
+        tx.write_all(b"\n\r\n{}\r\n\n[]").await.unwrap();
+        drop(tx);
+        assert_eq!(reader.read_frame().await.unwrap().unwrap(), b"{}");
+        // The last frame doesn't need a newline before the stream closes
+        assert_eq!(reader.read_frame().await.unwrap().unwrap(), b"[]");
+        assert!(reader.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn ndjson_too_large() {
+        let (mut tx, rx) = duplex(64 * 1024);
+        let mut reader = FrameReader::new(rx, Framing::Ndjson);
+        tokio::spawn(async move {
+            let chunk = vec![b'a'; 64 * 1024];
+            for _ in 0..=MAX_FRAME_SIZE / chunk.len() {
+                if tx.write_all(&chunk).await.is_err() {
+                    return;
+                }
+            }
+        });
+        let err = reader.read_frame().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_round_trip() {
+        let (mut tx, rx) = duplex(64);
+        let mut reader = FrameReader::new(rx, Framing::LengthPrefixed);
+        write_frame(&mut tx, Framing::LengthPrefixed, b"{\"a\":\"\n\"}")
+            .await
+            .unwrap();
+        write_frame(&mut tx, Framing::LengthPrefixed, b"{}")
+            .await
+            .unwrap();
+        drop(tx);
+        assert_eq!(
+            reader.read_frame().await.unwrap().unwrap(),
+            b"{\"a\":\"\n\"}"
+        );
+        assert_eq!(reader.read_frame().await.unwrap().unwrap(), b"{}");
+        assert!(reader.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_too_large() {
+        let (mut tx, rx) = duplex(64);
+        let mut reader = FrameReader::new(rx, Framing::LengthPrefixed);
+        tx.write_u32(MAX_FRAME_SIZE as u32 + 1).await.unwrap();
+        let err = reader.read_frame().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn typed_request() {
+        let envelope = Envelope::parse(
+            br#"{"type": "send_message", "request_id": "7", "chat_id": "1", "content": "hi"}"#,
+        )
+        .unwrap();
+        assert_eq!(envelope.request_id.as_deref(), Some("7"));
+        assert_eq!(
+            envelope.request,
+            Request::SendMessage {
+                chat_id: "1".to_string(),
+                content: "hi".to_string(),
+                reply_to: None,
+            }
+        );
+    }
+
+    #[test]
+    fn legacy_sender_commands() {
+        let parse = |frame: &str| Envelope::parse(frame.as_bytes()).unwrap();
+
+        let envelope = parse(r#"{"sender": "<restart>", "content": "", "chat_id": ""}"#);
+        assert_eq!(envelope.request_id, None);
+        assert_eq!(
+            envelope.request,
+            Request::Command {
+                command: Command::Restart
+            }
+        );
+        assert_eq!(
+            parse(r#"{"sender": "<file>", "content": "/tmp/a.png", "chat_id": "1"}"#).request,
+            Request::SendFile {
+                chat_id: "1".to_string(),
+                path: "/tmp/a.png".to_string(),
+            }
+        );
+        assert_eq!(
+            parse(r#"{"sender": "bot", "content": "hi", "chat_id": "1"}"#).request,
+            Request::SendMessage {
+                chat_id: "1".to_string(),
+                content: "hi".to_string(),
+                reply_to: None,
+            }
+        );
+        assert!(Envelope::parse(br#"{"type": "nonsense"}"#).is_err());
+    }
+}
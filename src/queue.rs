@@ -0,0 +1,205 @@
+// Jackson Coxson
+// Bounded per-client queues of events waiting to be written to the socket.
+// Pushing never waits, so one slow child can't hold up the main loop or anyone else,
+// and since everything is pushed from one place, every child sees events in the same order.
+
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::protocol::Event;
+
+/// What to do when a child's queue is full
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Make room by dropping the oldest queued event
+    #[default]
+    DropOldest,
+    /// Drop the event that didn't fit
+    DropNewest,
+    /// Give up on the child and close its connection
+    Disconnect,
+}
+
+pub struct EventQueue {
+    events: std::sync::Mutex<VecDeque<Event>>,
+    notify: Notify,
+    closed: AtomicBool,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            events: std::sync::Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            capacity: capacity.max(1),
+            overflow,
+        }
+    }
+
+    /// Queues an event, applying the overflow policy if full.
+    /// Returns false once the queue is closed.
+    pub fn push(&self, event: Event) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        {
+            let mut events = self.events.lock().unwrap();
+            if events.len() >= self.capacity {
+                match self.overflow {
+                    Overflow::DropOldest => {
+                        warn!("Client queue is full, dropping the oldest event");
+                        events.pop_front();
+                    }
+                    Overflow::DropNewest => {
+                        warn!("Client queue is full, dropping the newest event");
+                        return true;
+                    }
+                    Overflow::Disconnect => {
+                        warn!("Client queue is full, disconnecting it");
+                        drop(events);
+                        self.close();
+                        return false;
+                    }
+                }
+            }
+            events.push_back(event);
+        }
+        self.notify.notify_one();
+        true
+    }
+
+    /// Queues an event no matter how full the queue is, for replays the child asked for
+    pub fn push_unbounded(&self, event: Event) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        self.events.lock().unwrap().push_back(event);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Waits for the next event. Returns `None` once the queue is closed.
+    /// Safe to cancel, nothing is taken off the queue until it's returned.
+    pub async fn pop(&self) -> Option<Event> {
+        loop {
+            let notified = self.notify.notified();
+            if self.is_closed() {
+                return None;
+            }
+            if let Some(event) = self.events.lock().unwrap().pop_front() {
+                return Some(event);
+            }
+            notified.await;
+        }
+    }
+
+    /// Stops the queue, waking up whoever is waiting on it
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    fn event(id: &str) -> Event {
+        Event::Ack {
+            request_id: id.to_string(),
+            result: None,
+        }
+    }
+
+    /// Takes everything queued right now, without waiting for more
+    async fn drain(queue: &EventQueue) -> Vec<String> {
+        let mut ids = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::ZERO, queue.pop()).await {
+            match event {
+                Event::Ack { request_id, .. } => ids.push(request_id),
+                _ => unreachable!(),
+            }
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room() {
+        let queue = EventQueue::new(2, Overflow::DropOldest);
+        assert!(queue.push(event("a")));
+        assert!(queue.push(event("b")));
+        assert!(queue.push(event("c")));
+        assert_eq!(drain(&queue).await, ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_queue() {
+        let queue = EventQueue::new(2, Overflow::DropNewest);
+        assert!(queue.push(event("a")));
+        assert!(queue.push(event("b")));
+        assert!(queue.push(event("c")));
+        assert_eq!(drain(&queue).await, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue() {
+        let queue = EventQueue::new(1, Overflow::Disconnect);
+        assert!(queue.push(event("a")));
+        assert!(!queue.push(event("b")));
+        assert!(queue.is_closed());
+        assert!(!queue.push(event("c")));
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn push_unbounded_ignores_capacity() {
+        let queue = EventQueue::new(1, Overflow::Disconnect);
+        assert!(queue.push_unbounded(event("a")));
+        assert!(queue.push_unbounded(event("b")));
+        assert!(!queue.is_closed());
+        assert_eq!(drain(&queue).await, ["a", "b"]);
+
+        queue.close();
+        assert!(!queue.push_unbounded(event("c")));
+    }
+
+    #[tokio::test]
+    async fn pop_wakes_up_for_push_and_close() {
+        let queue = Arc::new(EventQueue::new(4, Overflow::DropOldest));
+
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await }
+        });
+        tokio::task::yield_now().await;
+        queue.push(event("a"));
+        let popped = tokio::time::timeout(Duration::from_secs(1), waiting).await;
+        assert!(matches!(popped, Ok(Ok(Some(Event::Ack { .. })))));
+
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await }
+        });
+        tokio::task::yield_now().await;
+        queue.close();
+        let popped = tokio::time::timeout(Duration::from_secs(1), waiting).await;
+        assert!(matches!(popped, Ok(Ok(None))));
+    }
+}
@@ -0,0 +1,178 @@
+// Jackson Coxson
+// Token buckets that keep bots from sending fast enough to get the account flagged.
+// Every send takes a token from the global bucket and from its chat's bucket.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Rate, RateLimit};
+
+/// What a send rejected for being over the limit is reported with
+pub const LIMITED: &str = "Rate limited";
+
+/// What to do with a send that's over the limit
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnLimit {
+    /// Leave it in the outbox until there's a token for it
+    #[default]
+    Queue,
+    /// Drop it and send the child an error
+    Reject,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    rate: Rate,
+}
+
+pub struct RateLimiter {
+    config: RateLimit,
+    global: Option<Bucket>,
+    chats: HashMap<String, Bucket>,
+}
+
+impl Bucket {
+    fn new(rate: Rate) -> Self {
+        Self {
+            tokens: rate.burst as f64,
+            last: Instant::now(),
+            rate,
+        }
+    }
+
+    /// Tops the bucket up, then returns how long until there's a whole token in it
+    fn wait(&mut self, now: Instant) -> Duration {
+        let per_sec = self.rate.per_minute.max(1) as f64 / 60.0;
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * per_sec)
+            .min(self.rate.burst.max(1) as f64);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / per_sec)
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimit) -> Self {
+        Self {
+            config: config.clone(),
+            global: config.global.map(Bucket::new),
+            chats: HashMap::new(),
+        }
+    }
+
+    pub fn on_limit(&self) -> OnLimit {
+        self.config.on_limit
+    }
+
+    /// Takes a token for a send to the chat.
+    /// If either bucket is empty, nothing is taken and the wait until there's room is returned.
+    pub fn take(&mut self, chat_id: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let rate = self
+            .config
+            .chats
+            .get(chat_id)
+            .or(self.config.per_chat.as_ref())
+            .copied();
+        let mut chat = rate.map(|rate| {
+            self.chats
+                .entry(chat_id.to_string())
+                .or_insert_with(|| Bucket::new(rate))
+        });
+
+        let mut wait = Duration::ZERO;
+        if let Some(chat) = chat.as_mut() {
+            wait = wait.max(chat.wait(now));
+        }
+        if let Some(global) = &mut self.global {
+            wait = wait.max(global.wait(now));
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        if let Some(chat) = chat {
+            chat.tokens -= 1.0;
+        }
+        if let Some(global) = &mut self.global {
+            global.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(burst: u32, per_minute: u32) -> Rate {
+        Rate { burst, per_minute }
+    }
+
+    #[test]
+    fn bucket_refills_up_to_its_burst() {
+        let mut bucket = Bucket::new(rate(2, 60));
+        let start = bucket.last;
+        assert_eq!(bucket.wait(start), Duration::ZERO);
+
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait(start), Duration::from_secs(1));
+        assert_eq!(
+            bucket.wait(start + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(bucket.wait(start + Duration::from_secs(10)), Duration::ZERO);
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn burst_then_wait() {
+        let mut limiter = RateLimiter::new(&RateLimit {
+            per_chat: Some(rate(2, 60)),
+            ..Default::default()
+        });
+        assert!(limiter.take("a").is_ok());
+        assert!(limiter.take("a").is_ok());
+        let wait = limiter.take("a").unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        // Every chat has its own bucket
+        assert!(limiter.take("b").is_ok());
+    }
+
+    #[test]
+    fn chat_override() {
+        let mut limiter = RateLimiter::new(&RateLimit {
+            per_chat: Some(rate(1, 1)),
+            chats: HashMap::from([("vip".to_string(), rate(3, 1))]),
+            ..Default::default()
+        });
+        for _ in 0..3 {
+            assert!(limiter.take("vip").is_ok());
+        }
+        assert!(limiter.take("vip").is_err());
+        assert!(limiter.take("a").is_ok());
+        assert!(limiter.take("a").is_err());
+    }
+
+    #[test]
+    fn global_limit_doesnt_spend_chat_tokens() {
+        let mut limiter = RateLimiter::new(&RateLimit {
+            global: Some(rate(1, 1)),
+            per_chat: Some(rate(1, 1)),
+            ..Default::default()
+        });
+        assert!(limiter.take("a").is_ok());
+        let wait = limiter.take("b").unwrap_err();
+        assert!(wait > Duration::from_secs(59));
+        assert_eq!(limiter.chats["b"].tokens, 1.0);
+    }
+}
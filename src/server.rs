@@ -0,0 +1,690 @@
+// Jackson Coxson
+// Accepts children on the socket and shuttles frames between them and the main loop.
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    sync::Arc,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use regex::Regex;
+use serde_json::json;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener},
+    sync::mpsc::Sender,
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request as Handshake, Response},
+    http::StatusCode,
+    Message,
+};
+
+use crate::{
+    chat::ChatMessage,
+    config::{Config, Token},
+    history::History,
+    protocol::{
+        write_frame, Envelope, Event, FrameReader, Framing, Inbound, Outcome, Request, Scope,
+        Subscription,
+    },
+    queue::{EventQueue, Overflow},
+    webhook::Webhooks,
+};
+
+/// Everyone currently connected
+pub type Clients = Arc<std::sync::Mutex<Vec<Arc<Client>>>>;
+
+/// State shared by every listener
+#[derive(Clone)]
+pub struct Hub {
+    pub clients: Clients,
+    tx: Sender<Inbound>,
+    tokens: Arc<Vec<Token>>,
+    webhooks: Arc<Webhooks>,
+    replay: Arc<std::sync::Mutex<Replay>>,
+    pub history: Arc<History>,
+    queue_size: usize,
+    overflow: Overflow,
+}
+
+/// The most recent messages, kept so reconnecting children can catch up
+struct Replay {
+    /// When this process started, so sequence numbers from before a restart aren't mistaken for ours
+    epoch: u64,
+    next_seq: u64,
+    messages: VecDeque<(u64, ChatMessage)>,
+    capacity: usize,
+}
+
+/// A connected child
+pub struct Client {
+    pub queue: Arc<EventQueue>,
+    filter: std::sync::Mutex<Filter>,
+    scopes: std::sync::Mutex<Vec<Scope>>,
+}
+
+/// A subscription with its patterns compiled
+#[derive(Default)]
+struct Filter {
+    chat_ids: Vec<String>,
+    senders: Vec<String>,
+    patterns: Vec<Regex>,
+    reactions: bool,
+}
+
+impl Hub {
+    pub fn new(tx: Sender<Inbound>, config: &Config) -> Self {
+        Self {
+            clients: Clients::default(),
+            tx,
+            tokens: Arc::new(config.tokens.clone()),
+            webhooks: Arc::new(Webhooks::new(config.webhooks.clone())),
+            replay: Arc::new(std::sync::Mutex::new(Replay {
+                epoch: chrono::Utc::now().timestamp_millis() as u64,
+                next_seq: 1,
+                messages: VecDeque::new(),
+                capacity: config.replay_buffer,
+            })),
+            history: Arc::new(History::new(&config.history)),
+            queue_size: config.broadcast.queue_size,
+            overflow: config.broadcast.overflow,
+        }
+    }
+
+    /// Numbers a new message, keeps it for replay and in the history,
+    /// and queues it for every child and webhook that wants it.
+    /// Never waits on a child, and children see messages in the order they were published.
+    pub fn publish(&self, mut message: ChatMessage) {
+        let now = chrono::Utc::now();
+        message.detected_at = Some(now);
+        message.sent_at.get_or_insert(now);
+
+        // Holding the replay lock keeps a resuming child from seeing this twice
+        let mut replay = self.replay.lock().unwrap();
+        let seq = replay.push(message.clone());
+        self.history.received(&message);
+        let event = Event::Message {
+            epoch: replay.epoch,
+            seq,
+            message: message.clone(),
+        };
+        self.webhooks.deliver(&event);
+
+        self.clients.lock().unwrap().retain(|client| {
+            if !client.wants(&message) || client.queue.push(event.clone()) {
+                true
+            } else {
+                client.queue.close();
+                false
+            }
+        });
+    }
+
+    /// Queues a message whose reactions changed for every child that subscribed to reactions
+    /// and wants the message, with `detected_at` set to when the change was noticed.
+    /// These aren't numbered or kept for replay, the next change carries the full set anyway.
+    pub fn publish_reactions(&self, mut message: ChatMessage) {
+        let now = chrono::Utc::now();
+        message.detected_at = Some(now);
+        message.sent_at.get_or_insert(now);
+        let event = Event::Reactions {
+            message: message.clone(),
+        };
+        self.clients.lock().unwrap().retain(|client| {
+            if !client.wants_reactions(&message) || client.queue.push(event.clone()) {
+                true
+            } else {
+                client.queue.close();
+                false
+            }
+        });
+    }
+
+    /// Registers a new child. Without any tokens configured, everyone gets every scope.
+    fn connect(&self) -> Arc<Client> {
+        let scopes = if !self.requires_auth() {
+            Scope::ALL.to_vec()
+        } else {
+            Vec::new()
+        };
+        let client = Arc::new(Client {
+            queue: Arc::new(EventQueue::new(self.queue_size, self.overflow)),
+            filter: std::sync::Mutex::new(Filter::default()),
+            scopes: std::sync::Mutex::new(scopes),
+        });
+        self.clients.lock().unwrap().push(client.clone());
+        client
+    }
+
+    fn disconnect(&self, client: &Arc<Client>) {
+        client.queue.close();
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|c| !Arc::ptr_eq(c, client));
+    }
+
+    /// Whether any tokens are configured at all
+    pub fn requires_auth(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// The scopes granted by a token, if it's one of ours
+    pub fn scopes_for(&self, token: &str) -> Option<Vec<Scope>> {
+        self.tokens
+            .iter()
+            .find(|t| t.token == token)
+            .map(|t| t.scopes.clone())
+    }
+
+    /// Passes a request to the main loop on behalf of someone who isn't a connected child,
+    /// and waits up to `wait` for it to be carried out
+    pub async fn dispatch(
+        &self,
+        mut request: Request,
+        scopes: Vec<Scope>,
+        wait: std::time::Duration,
+    ) -> Result<Outcome, Unanswered> {
+        let reply = Arc::new(EventQueue::new(1, Overflow::DropOldest));
+        request.clean();
+        self.tx
+            .send(Inbound {
+                request_id: Some(String::new()),
+                request,
+                reply: reply.clone(),
+                scopes,
+            })
+            .await
+            .map_err(|_| Unanswered::NotRunning)?;
+
+        match tokio::time::timeout(wait, reply.pop()).await {
+            Ok(Some(Event::Ack { result, .. })) => Ok(Ok(result)),
+            Ok(Some(Event::Error { message, .. })) => Ok(Err(message)),
+            Ok(_) => Err(Unanswered::Dropped),
+            Err(_) => Err(Unanswered::TimedOut),
+        }
+    }
+}
+
+/// Why a dispatched request has no outcome
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unanswered {
+    NotRunning,
+    Dropped,
+    /// Holly is still working on it, or it's waiting in the outbox, and may yet be carried out
+    TimedOut,
+}
+
+impl std::fmt::Display for Unanswered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Unanswered::NotRunning => write!(f, "Holly is not running"),
+            Unanswered::Dropped => write!(f, "Holly dropped the request"),
+            Unanswered::TimedOut => write!(f, "Timed out waiting for Holly"),
+        }
+    }
+}
+
+impl Replay {
+    fn push(&mut self, message: ChatMessage) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.messages.push_back((seq, message));
+        while self.messages.len() > self.capacity {
+            self.messages.pop_front();
+        }
+        seq
+    }
+
+    /// Every message after `seq`, and whether none were dropped from the buffer in between.
+    /// A `seq` from another epoch, or from the future without one, must be from before Holly restarted,
+    /// so everything is replayed.
+    fn since(&self, epoch: Option<u64>, seq: u64) -> (Vec<(u64, ChatMessage)>, bool) {
+        if epoch.is_some_and(|e| e != self.epoch) || seq >= self.next_seq {
+            return (self.messages.iter().cloned().collect(), false);
+        }
+        let oldest = self.messages.front().map_or(self.next_seq, |(s, _)| *s);
+        let messages = self
+            .messages
+            .iter()
+            .filter(|(s, _)| *s > seq)
+            .cloned()
+            .collect();
+        (messages, oldest <= seq + 1)
+    }
+}
+
+impl Client {
+    /// Whether the child asked to hear about this message, and is allowed to
+    pub fn wants(&self, message: &ChatMessage) -> bool {
+        self.has_scope(Scope::Read) && self.filter.lock().unwrap().matches(message)
+    }
+
+    /// Whether the child subscribed to reaction changes on this message
+    fn wants_reactions(&self, message: &ChatMessage) -> bool {
+        self.filter.lock().unwrap().reactions && self.wants(message)
+    }
+
+    fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.lock().unwrap().contains(&scope)
+    }
+
+    /// Reports the outcome of a request handled by the server itself
+    fn report(&self, request_id: Option<String>, result: Outcome) {
+        if let Err(e) = &result {
+            warn!("Request {request_id:?} failed: {e}");
+        }
+        if let Some(request_id) = request_id {
+            self.queue.push(Event::outcome(request_id, result));
+        }
+    }
+}
+
+impl Filter {
+    fn compile(subscription: Subscription) -> Result<Self, regex::Error> {
+        Ok(Self {
+            chat_ids: subscription.chat_ids,
+            senders: subscription.senders,
+            patterns: subscription
+                .patterns
+                .iter()
+                .map(|p| Regex::new(p))
+                .collect::<Result<_, _>>()?,
+            reactions: subscription.reactions,
+        })
+    }
+
+    /// Every list that isn't empty needs at least one match
+    fn matches(&self, message: &ChatMessage) -> bool {
+        (self.chat_ids.is_empty() || self.chat_ids.contains(&message.chat_id))
+            && (self.senders.is_empty() || self.senders.contains(&message.sender))
+            && (self.patterns.is_empty()
+                || self.patterns.iter().any(|p| p.is_match(&message.content)))
+    }
+}
+
+/// Accepts children on the TCP socket forever
+pub async fn listen_tcp(listener: TcpListener, framing: Framing, hub: Hub) {
+    loop {
+        if let Ok((stream, addr)) = listener.accept().await {
+            info!("Accepted connection from {:?}", addr);
+            tokio::spawn(handle_connection(stream, addr, framing, hub.clone()));
+        }
+    }
+}
+
+/// Binds a Unix domain socket, replacing whatever stale socket was left at the path.
+/// Anything at the path that isn't a socket is left alone.
+pub fn bind_unix(path: &str, mode: Option<u32>) -> std::io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path)?;
+            info!("Removed stale socket at {path}");
+        }
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{path} exists and isn't a socket"),
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let Some(mode) = mode else {
+        return UnixListener::bind(path);
+    };
+
+    // Bound in a directory only we can get into, and moved into place once its mode is set,
+    // so nobody can connect while it still has the umask's permissions
+    let parent = match std::path::Path::new(path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => std::path::Path::new("."),
+    };
+    let staging = parent.join(format!(".holly-{}", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    std::fs::remove_dir(&staging)?;
+    bound
+}
+
+/// Accepts children on the Unix domain socket forever
+pub async fn listen_unix(listener: UnixListener, framing: Framing, hub: Hub) {
+    loop {
+        if let Ok((stream, addr)) = listener.accept().await {
+            info!("Accepted connection from {:?}", addr);
+            tokio::spawn(handle_connection(stream, addr, framing, hub.clone()));
+        }
+    }
+}
+
+/// Serves a single child until it disconnects
+async fn handle_connection<S, A>(stream: S, addr: A, framing: Framing, hub: Hub)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    A: Debug + Send + 'static,
+{
+    let client = hub.connect();
+
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    // Reading gets its own task, since a half read frame can't be cancelled safely
+    let reader_client = client.clone();
+    let reader_hub = hub.clone();
+    let mut reader_task = tokio::spawn(async move {
+        let mut reader = FrameReader::new(reader, framing);
+        loop {
+            let frame = match reader.read_frame().await {
+                Ok(Some(f)) => f,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Failed to read frame: {e:?}");
+                    break;
+                }
+            };
+            reader_hub.handle_frame(&reader_client, &frame).await;
+        }
+    });
+
+    loop {
+        tokio::select! {
+            msg = client.queue.pop() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                let msg = serde_json::to_vec(&msg).unwrap();
+                if let Err(e) = write_frame(&mut writer, framing, &msg).await {
+                    warn!("Unable to write message to client: {e:?}");
+                    break;
+                }
+            }
+            _ = &mut reader_task => break,
+        }
+    }
+    reader_task.abort();
+    hub.disconnect(&client);
+    info!("Connection from {:?} closed", addr);
+}
+
+/// Accepts children over WebSocket forever.
+/// Each text or binary message carries one JSON object, just like a frame on the socket.
+/// Browsers say which page is connecting, and only the allowed origins are let in,
+/// so any website the user visits can't drive Holly.
+pub async fn listen_websocket(listener: TcpListener, allowed_origins: Vec<String>, hub: Hub) {
+    let allowed_origins = Arc::new(allowed_origins);
+    loop {
+        if let Ok((stream, addr)) = listener.accept().await {
+            info!("Accepted WebSocket connection from {:?}", addr);
+            tokio::spawn(handle_websocket(
+                stream,
+                addr,
+                allowed_origins.clone(),
+                hub.clone(),
+            ));
+        }
+    }
+}
+
+/// Serves a single WebSocket child until it disconnects
+async fn handle_websocket(
+    stream: TcpStream,
+    addr: SocketAddr,
+    allowed_origins: Arc<Vec<String>>,
+    hub: Hub,
+) {
+    // The error type is tungstenite's to choose
+    #[allow(clippy::result_large_err)]
+    let check_origin = |request: &Handshake, response: Response| {
+        // Clients that aren't browsers don't send an origin
+        let Some(origin) = request.headers().get("origin") else {
+            return Ok(response);
+        };
+        if allowed_origins
+            .iter()
+            .any(|a| a.as_bytes() == origin.as_bytes())
+        {
+            return Ok(response);
+        }
+        warn!("Refusing WebSocket connection from {addr:?} with origin {origin:?}");
+        let mut response = ErrorResponse::new(Some("Origin not allowed".to_string()));
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        Err(response)
+    };
+    let ws = match tokio_tungstenite::accept_hdr_async(stream, check_origin).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            warn!("WebSocket handshake with {addr:?} failed: {e:?}");
+            return;
+        }
+    };
+    let (mut writer, mut reader) = ws.split();
+
+    let client = hub.connect();
+
+    let reader_client = client.clone();
+    let reader_hub = hub.clone();
+    let mut reader_task = tokio::spawn(async move {
+        while let Some(msg) = reader.next().await {
+            match msg {
+                Ok(Message::Text(text)) => {
+                    reader_hub
+                        .handle_frame(&reader_client, text.as_bytes())
+                        .await
+                }
+                Ok(Message::Binary(data)) => reader_hub.handle_frame(&reader_client, &data).await,
+                Ok(Message::Close(_)) => break,
+                // Pings are answered by tungstenite
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to read WebSocket message: {e:?}");
+                    break;
+                }
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            msg = client.queue.pop() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                let msg = serde_json::to_string(&msg).unwrap();
+                if let Err(e) = writer.send(Message::Text(msg)).await {
+                    warn!("Unable to write message to WebSocket client: {e:?}");
+                    break;
+                }
+            }
+            _ = &mut reader_task => break,
+        }
+    }
+    reader_task.abort();
+    let _ = writer.close().await;
+    hub.disconnect(&client);
+    info!("WebSocket connection from {:?} closed", addr);
+}
+
+impl Hub {
+    /// Handles a request frame from a child.
+    /// Anything that needs the browser is passed on to the main loop.
+    async fn handle_frame(&self, client: &Client, frame: &[u8]) {
+        let Envelope {
+            request_id,
+            mut request,
+        } = match Envelope::parse(frame) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!(
+                    "Failed to parse msg: {:?} ({e})",
+                    String::from_utf8_lossy(frame)
+                );
+                if let Some(request_id) = Envelope::request_id_of(frame) {
+                    client.report(Some(request_id), Err(e.to_string()));
+                }
+                return;
+            }
+        };
+
+        match request {
+            Request::Auth { token } => {
+                let result = match self.scopes_for(&token) {
+                    Some(scopes) => {
+                        info!("Client authenticated with scopes {:?}", scopes);
+                        *client.scopes.lock().unwrap() = scopes;
+                        Ok(None)
+                    }
+                    None => Err("Invalid token".to_string()),
+                };
+                client.report(request_id, result);
+            }
+            Request::Resume { resume_from, epoch } => {
+                if !client.has_scope(Scope::Read) {
+                    let result = Err(format!("Missing the {} scope", Scope::Read));
+                    client.report(request_id, result);
+                    return;
+                }
+                // Publishing waits on the replay lock, so nothing can slip in between
+                let replay = self.replay.lock().unwrap();
+                let (messages, complete) = replay.since(epoch, resume_from);
+                let mut replayed = 0;
+                for (seq, message) in messages {
+                    if client.wants(&message) {
+                        replayed += 1;
+                        client.queue.push_unbounded(Event::Message {
+                            epoch: replay.epoch,
+                            seq,
+                            message,
+                        });
+                    }
+                }
+                let result = json!({
+                    "replayed": replayed,
+                    "complete": complete,
+                    "epoch": replay.epoch,
+                });
+                drop(replay);
+                info!("Replayed {replayed} messages from {resume_from}");
+                client.report(request_id, Ok(Some(result)));
+            }
+            Request::History(query) => {
+                if !client.has_scope(Scope::Read) {
+                    let result = Err(format!("Missing the {} scope", Scope::Read));
+                    client.report(request_id, result);
+                    return;
+                }
+                let history = self.history.clone();
+                let result = match tokio::task::spawn_blocking(move || history.search(&query)).await
+                {
+                    Ok(Ok(entries)) => Ok(Some(json!({ "messages": entries }))),
+                    Ok(Err(e)) => Err(format!("Unable to read the history: {e}")),
+                    Err(e) => Err(format!("History search failed: {e}")),
+                };
+                client.report(request_id, result);
+            }
+            Request::Subscribe(subscription) => {
+                let result = if !client.has_scope(Scope::Read) {
+                    Err(format!("Missing the {} scope", Scope::Read))
+                } else {
+                    match Filter::compile(subscription) {
+                        Ok(filter) => {
+                            *client.filter.lock().unwrap() = filter;
+                            Ok(None)
+                        }
+                        Err(e) => Err(format!("Invalid pattern: {e}")),
+                    }
+                };
+                client.report(request_id, result);
+            }
+            _ => {
+                request.clean();
+                let scopes = client.scopes.lock().unwrap().clone();
+                self.tx
+                    .send(Inbound {
+                        request_id,
+                        request,
+                        reply: client.queue.clone(),
+                        scopes,
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(capacity: usize, messages: usize) -> Replay {
+        let mut replay = Replay {
+            epoch: 7,
+            next_seq: 1,
+            messages: VecDeque::new(),
+            capacity,
+        };
+        for i in 0..messages {
+            replay.push(ChatMessage {
+                sender: "Jackson Coxson".to_string(),
+                content: i.to_string(),
+                chat_id: "1".to_string(),
+                message_id: None,
+                reply_to: None,
+                attachments: Vec::new(),
+                reactions: Vec::new(),
+                from_self: false,
+                sent_at: None,
+                detected_at: None,
+            });
+        }
+        replay
+    }
+
+    fn seqs(since: (Vec<(u64, ChatMessage)>, bool)) -> (Vec<u64>, bool) {
+        (since.0.into_iter().map(|(s, _)| s).collect(), since.1)
+    }
+
+    #[test]
+    fn empty_buffer() {
+        let replay = replay(10, 0);
+        assert_eq!(seqs(replay.since(Some(7), 0)), (vec![], true));
+    }
+
+    #[test]
+    fn caught_up() {
+        let replay = replay(10, 5);
+        assert_eq!(seqs(replay.since(Some(7), 3)), (vec![4, 5], true));
+        assert_eq!(seqs(replay.since(None, 5)), (vec![], true));
+    }
+
+    #[test]
+    fn evicted_range() {
+        // Only 6 to 8 are left
+        let replay = replay(3, 8);
+        assert_eq!(seqs(replay.since(Some(7), 2)), (vec![6, 7, 8], false));
+        assert_eq!(seqs(replay.since(Some(7), 5)), (vec![6, 7, 8], true));
+    }
+
+    #[test]
+    fn future_seq() {
+        let replay = replay(10, 3);
+        assert_eq!(seqs(replay.since(None, 50)), (vec![1, 2, 3], false));
+    }
+
+    #[test]
+    fn other_epoch() {
+        // A seq we've already passed, but counted before a restart
+        let replay = replay(10, 5);
+        assert_eq!(seqs(replay.since(Some(6), 2)), (vec![1, 2, 3, 4, 5], false));
+    }
+}
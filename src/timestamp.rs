@@ -0,0 +1,108 @@
+// Jackson Coxson
+// Messenger labels messages with human readable times, like "Yesterday at 9:41 PM",
+// "Mon 9:41 PM" or "May 3, 2024, 9:41 PM", in the browser's local time.
+// This turns them back into something we can compare.
+
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+
+/// Parses a time label from the page, in the browser's time zone
+pub fn parse_label(label: &str) -> Option<DateTime<Utc>> {
+    let naive = parse_naive(label, Local::now().date_naive())?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Parses a time label relative to `today`
+fn parse_naive(label: &str, today: NaiveDate) -> Option<NaiveDateTime> {
+    let label = label.replace(',', " ").replace(" at ", " ");
+    let mut words: Vec<&str> = label.split_whitespace().collect();
+
+    // The time is always last, with or without AM/PM
+    let time = match words.last()?.to_uppercase().as_str() {
+        "AM" | "PM" => {
+            let time = format!("{} {}", words[words.len().checked_sub(2)?], words.last()?);
+            words.truncate(words.len() - 2);
+            NaiveTime::parse_from_str(&time.to_uppercase(), "%I:%M %p").ok()?
+        }
+        _ => NaiveTime::parse_from_str(words.pop()?, "%H:%M").ok()?,
+    };
+
+    let date = match words.as_slice() {
+        [] => today,
+        [day] if day.eq_ignore_ascii_case("today") => today,
+        [day] if day.eq_ignore_ascii_case("yesterday") => today.checked_sub_days(Days::new(1))?,
+        [day] => match day.parse::<chrono::Weekday>() {
+            // A weekday means the last one before today
+            Ok(weekday) => (1..=7)
+                .filter_map(|d| today.checked_sub_days(Days::new(d)))
+                .find(|d| d.weekday() == weekday)?,
+            Err(_) => NaiveDate::parse_from_str(day, "%m/%d/%y")
+                .or_else(|_| NaiveDate::parse_from_str(day, "%m/%d/%Y"))
+                .ok()?,
+        },
+        [month, day] => {
+            // No year means the last time it was that day
+            let date = parse_date(&format!("{month} {day} {}", today.year()))?;
+            if date > today {
+                parse_date(&format!("{month} {day} {}", today.year() - 1))?
+            } else {
+                date
+            }
+        }
+        [month, day, year] => parse_date(&format!("{month} {day} {year}"))?,
+        _ => return None,
+    };
+    Some(date.and_time(time))
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%B %d %Y")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%b %d %Y"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(label: &str) -> Option<String> {
+        let today = NaiveDate::from_ymd_opt(2024, 5, 8).unwrap(); // A Wednesday
+        parse_naive(label, today).map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+    }
+
+    #[test]
+    fn time_only() {
+        assert_eq!(at("9:41 PM").as_deref(), Some("2024-05-08 21:41"));
+        assert_eq!(at("21:41").as_deref(), Some("2024-05-08 21:41"));
+    }
+
+    #[test]
+    fn relative_days() {
+        assert_eq!(at("Today at 9:41 AM").as_deref(), Some("2024-05-08 09:41"));
+        assert_eq!(
+            at("Yesterday at 12:05 AM").as_deref(),
+            Some("2024-05-07 00:05")
+        );
+        assert_eq!(at("Mon 9:41 PM").as_deref(), Some("2024-05-06 21:41"));
+        assert_eq!(at("Wednesday 9:41 PM").as_deref(), Some("2024-05-01 21:41"));
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(at("May 3 at 9:41 PM").as_deref(), Some("2024-05-03 21:41"));
+        assert_eq!(at("Dec 24, 9:41 PM").as_deref(), Some("2023-12-24 21:41"));
+        assert_eq!(
+            at("May 3, 2022, 9:41 PM").as_deref(),
+            Some("2022-05-03 21:41")
+        );
+        assert_eq!(at("5/3/22, 9:41 PM").as_deref(), Some("2022-05-03 21:41"));
+    }
+
+    #[test]
+    fn not_a_time() {
+        assert_eq!(at("hello there"), None);
+        assert_eq!(at(""), None);
+    }
+}
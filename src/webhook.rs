@@ -0,0 +1,132 @@
+// Jackson Coxson
+// POSTs new messages to configured URLs, for integrations that don't want to hold a socket open.
+// Bodies are the same `message` events children receive, optionally signed with HMAC-SHA256.
+
+use std::{sync::Arc, time::Duration};
+
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use sha2::Sha256;
+
+use crate::{config::Webhook, protocol::Event};
+
+/// Header carrying the hex encoded HMAC-SHA256 of the body
+const SIGNATURE_HEADER: &str = "X-Holly-Signature";
+
+pub struct Webhooks {
+    client: reqwest::Client,
+    hooks: Vec<Arc<Webhook>>,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            hooks: hooks.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// Sends a message event to every webhook that wants it, in the background
+    pub fn deliver(&self, event: &Event) {
+        let Event::Message { message, .. } = event else {
+            return;
+        };
+        let body = match serde_json::to_vec(event) {
+            Ok(b) => b,
+            Err(e) => {
+                warn!("Unable to serialize message for webhooks: {e:?}");
+                return;
+            }
+        };
+
+        for hook in &self.hooks {
+            if !wants(hook, &message.chat_id) {
+                continue;
+            }
+            tokio::spawn(post(self.client.clone(), hook.clone(), body.clone()));
+        }
+    }
+}
+
+/// Whether the hook takes messages from this chat
+fn wants(hook: &Webhook, chat_id: &str) -> bool {
+    hook.chat_ids.is_empty() || hook.chat_ids.iter().any(|c| c == chat_id)
+}
+
+/// The signature header's value, `sha256=<hex>`
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POSTs the body, retrying with exponential backoff
+async fn post(client: reqwest::Client, hook: Arc<Webhook>, body: Vec<u8>) {
+    let signature = hook.secret.as_deref().map(|secret| sign(secret, &body));
+
+    let mut backoff = Duration::from_millis(500);
+    for attempt in 0..=hook.retries {
+        let mut req = client
+            .post(&hook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(Duration::from_millis(hook.timeout_ms))
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            req = req.header(SIGNATURE_HEADER, signature);
+        }
+
+        match req.send().await {
+            Ok(res) if res.status().is_success() => {
+                debug!("Delivered message to webhook {}", hook.url);
+                return;
+            }
+            Ok(res) => warn!(
+                "Webhook {} responded with {} (attempt {})",
+                hook.url,
+                res.status(),
+                attempt + 1
+            ),
+            Err(e) => warn!(
+                "Unable to reach webhook {}: {e:?} (attempt {})",
+                hook.url,
+                attempt + 1
+            ),
+        }
+
+        if attempt < hook.retries {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    warn!("Giving up on delivering a message to webhook {}", hook.url);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn filters_by_chat() {
+        let mut hook = Webhook {
+            url: "http://127.0.0.1:9000/holly".to_string(),
+            chat_ids: Vec::new(),
+            secret: None,
+            timeout_ms: 5000,
+            retries: 3,
+        };
+        assert!(wants(&hook, "1234"));
+
+        hook.chat_ids = vec!["1234".to_string()];
+        assert!(wants(&hook, "1234"));
+        assert!(!wants(&hook, "5678"));
+    }
+}